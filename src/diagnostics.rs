@@ -0,0 +1,117 @@
+//! Rich, multi-label error reporting.
+//!
+//! A single [`Pos`] is rarely enough to explain a typed-language error: an
+//! arity mismatch wants to point at both the call site and the callee's
+//! declaration, and an effect violation wants to point at both the call
+//! site and the function's declared effect. A [`Diagnostic`] carries one
+//! primary [`Span`] plus any number of secondary labeled spans, and
+//! [`Diagnostic::render`] prints the source line(s) under each of them
+//! with a caret underline, given the original source text of every file
+//! it references.
+
+use crate::Span;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// a span labeled with why it is relevant to the diagnostic
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub msg: String,
+}
+
+/// a rich error: a short error code, a one-line message, a primary span,
+/// any number of secondary labeled spans, and an optional help note
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub code: String,
+    pub msg: String,
+    pub primary: Span,
+    pub labels: Vec<Label>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(code: &str, msg: String, primary: Span) -> Diagnostic {
+        Diagnostic {
+            code: code.to_string(),
+            msg,
+            primary,
+            labels: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn with_label(mut self, span: Span, msg: String) -> Diagnostic {
+        self.labels.push(Label { span, msg });
+        self
+    }
+
+    pub fn with_help(mut self, help: String) -> Diagnostic {
+        self.help = Some(help);
+        self
+    }
+
+    /// render this diagnostic, printing the offending source line(s) with
+    /// caret underlines beneath every labeled span. `sources` gives the
+    /// original text of every file this diagnostic may reference, as
+    /// `(file_id, source)` pairs.
+    pub fn render(&self, sources: &[(usize, &str)]) -> String {
+        let mut out = format!("error[{}]: {}\n", self.code, self.msg);
+        render_span(&mut out, self.primary, "here", sources);
+        for label in &self.labels {
+            render_span(&mut out, label.span, &label.msg, sources);
+        }
+        if let Some(help) = &self.help {
+            out.push_str(&format!("help: {}\n", help));
+        }
+        out
+    }
+}
+
+fn source_of<'a>(sources: &[(usize, &'a str)], file_id: usize) -> Option<&'a str> {
+    for (id, src) in sources {
+        if *id == file_id {
+            return Some(src);
+        }
+    }
+    None
+}
+
+fn render_span(out: &mut String, span: Span, label: &str, sources: &[(usize, &str)]) {
+    out.push_str(&format!(
+        "  --> <file {}>:{}:{}\n",
+        span.start.file_id,
+        span.start.line + 1,
+        span.start.column + 1
+    ));
+
+    let src = match source_of(sources, span.start.file_id) {
+        Some(s) => s,
+        None => return,
+    };
+    let line = match src.lines().nth(span.start.line) {
+        Some(l) => l,
+        None => return,
+    };
+
+    let underline_len = if span.start.line == span.end.line && span.end.column > span.start.column
+    {
+        span.end.column - span.start.column
+    } else {
+        1
+    };
+
+    out.push_str(&format!("   | {}\n", line));
+    out.push_str("   | ");
+    for _ in 0..span.start.column {
+        out.push(' ');
+    }
+    for _ in 0..underline_len {
+        out.push('^');
+    }
+    out.push(' ');
+    out.push_str(label);
+    out.push('\n');
+}
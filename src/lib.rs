@@ -34,25 +34,32 @@
 //!
 //! ### Foreign Function Interface
 //!
+//! Host functions are bound in by name with
+//! [`semantics::Context::register_extern`], rather than through a single
+//! fixed-signature callback: an embedder can register as many externs as
+//! it needs, with whatever arity and argument types.
+//!
 //! ```
-//! use blisp;
-//! use num_bigint::BigInt;
+//! use blisp::runtime::Value;
+//! use blisp::semantics::{FfiSig, FfiType};
 //!
 //! let expr = "
-//! (export callback (x y z)
+//! (export log-product (x y z)
 //!     (IO (-> (Int Int Int) (Option Int)))
-//!     (call-rust x y z))";
+//!     (multiply x y z))";
 //! let exprs = blisp::init(expr).unwrap();
 //! let mut ctx = blisp::typing(&exprs).unwrap();
 //!
-//! let fun = |x: &BigInt, y: &BigInt, z: &BigInt| {
-//!     let n = x * y * z;
-//!     println!("n = {}", n);
-//!     Some(n)
+//! let sig = FfiSig {
+//!     args: vec![FfiType::Int, FfiType::Int, FfiType::Int],
+//!     ret: FfiType::Option(Box::new(FfiType::Int)),
 //! };
-//! ctx.set_callback(Box::new(fun));
+//! ctx.register_extern("multiply", sig, Box::new(|args: &[Value]| {
+//!     println!("multiply({:?})", args);
+//!     Ok(Value::Adt("Some".to_string(), vec![args[0].clone()]))
+//! })).unwrap();
 //!
-//! let e = "(callback 100 2000 30000)";
+//! let e = "(log-product 100 2000 30000)";
 //! blisp::eval(e, &ctx);
 //! ```
 //!
@@ -64,15 +71,20 @@
 //! - Effect system to separate side effects from pure functions
 //! - Big integer
 //! - Supporting no_std environments
+//! - Optional bytecode VM (`bytecode-vm` feature) for repeated evaluation
+//! - Hygienic `defmacro` expansion, run between [`init`] and [`typing`]
 
 #![no_std]
 
-#[macro_use]
 extern crate alloc;
 
+use alloc::boxed::Box;
 use alloc::collections::linked_list::LinkedList;
+use alloc::format;
 use alloc::string::String;
 
+pub mod diagnostics;
+pub mod macros;
 pub mod parser;
 pub mod runtime;
 pub mod semantics;
@@ -80,6 +92,13 @@ pub mod semantics;
 const FILE_ID_PRELUD: usize = 0;
 const FILE_ID_USER: usize = 1;
 pub(crate) const FILE_ID_EVAL: usize = 2;
+/// synthetic file id used for `Pos`/`Span`s manufactured by [`macros::expand`]
+/// for nodes a macro template contributes, rather than ones spliced in
+/// from an argument at the use site
+pub(crate) const FILE_ID_MACRO: usize = 3;
+/// file id used for fragments parsed by [`semantics::Context::define`] and
+/// [`semantics::Context::infer_type`], e.g. a REPL's current input line
+pub(crate) const FILE_ID_REPL: usize = 4;
 
 /// indicate a position of file
 #[derive(Debug, Clone, Copy)]
@@ -89,16 +108,48 @@ pub struct Pos {
     pub column: usize,  // column number, 0 origin
 }
 
+/// a range of source positions, from the first character of a node up to
+/// the position just past its last character (exclusive)
+#[derive(Debug, Clone, Copy)]
+pub struct Span {
+    pub start: Pos,
+    pub end: Pos,
+}
+
 /// error message
+///
+/// `msg`/`pos` are always set, so existing callers that only looked at
+/// those two fields keep working unchanged. When the failure has more
+/// than one relevant location (e.g. a builtin argument of the wrong type,
+/// an arity mismatch, or a function called somewhere it isn't allowed), `diagnostic` carries the
+/// full [`diagnostics::Diagnostic`], which [`diagnostics::Diagnostic::render`]
+/// can print with source snippets and carets under every labeled span.
+/// Boxed so a bare `Result<_, LispErr>` stays small on the `Ok` path, which
+/// is the common one.
 #[derive(Debug)]
 pub struct LispErr {
     pub msg: String,
     pub pos: Pos,
+    pub diagnostic: Option<Box<diagnostics::Diagnostic>>,
+}
+
+impl core::fmt::Display for LispErr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
 }
 
 impl LispErr {
     fn new(msg: String, pos: Pos) -> LispErr {
-        LispErr { msg: msg, pos: pos }
+        LispErr { msg, pos, diagnostic: None }
+    }
+
+    fn from_diagnostic(d: diagnostics::Diagnostic) -> LispErr {
+        LispErr {
+            msg: d.msg.clone(),
+            pos: d.primary.start,
+            diagnostic: Some(Box::new(d)),
+        }
     }
 }
 
@@ -138,6 +189,49 @@ pub fn init(code: &str) -> Result<LinkedList<parser::Expr>, LispErr> {
     }
 }
 
+/// expand every `defmacro` in `exprs`, run between [`init`] and [`typing`]
+///
+/// Expansion is purely syntactic and hygienic: a macro's own `lambda`
+/// parameters and `match` bindings are renamed to fresh names once, when
+/// the macro is defined, so they can never capture, or be captured by,
+/// identifiers at a use site. The result is ordinary, already-expanded
+/// `export`/`defun`/`data` forms, so [`typing`] never has to know macros
+/// exist.
+///
+/// # Example
+///
+/// ```
+/// let code = "
+/// (defmacro sq (x) (* x x))
+///
+/// (export sum-of-squares (a b) (Pure (-> (Int Int) Int))
+///     (+ (sq a) (sq b)))";
+///
+/// let exprs = blisp::init(code).unwrap();
+/// let exprs = blisp::expand_macros(exprs).unwrap();
+/// blisp::typing(&exprs).unwrap();
+/// ```
+pub fn expand_macros(
+    exprs: LinkedList<parser::Expr>,
+) -> Result<LinkedList<parser::Expr>, LispErr> {
+    macros::expand(exprs).map_err(|e| {
+        let msg = format!("Macro Error: {}", e.msg);
+        LispErr::new(msg, e.pos)
+    })
+}
+
+/// like [`expand_macros`], but with a caller-chosen expansion depth limit
+/// instead of [`macros::DEFAULT_MAX_EXPANSION_DEPTH`]
+pub fn expand_macros_with_limit(
+    exprs: LinkedList<parser::Expr>,
+    max_depth: usize,
+) -> Result<LinkedList<parser::Expr>, LispErr> {
+    macros::expand_with_limit(exprs, max_depth).map_err(|e| {
+        let msg = format!("Macro Error: {}", e.msg);
+        LispErr::new(msg, e.pos)
+    })
+}
+
 /// perform type checking and inference
 ///
 /// # Example
@@ -152,13 +246,7 @@ pub fn init(code: &str) -> Result<LinkedList<parser::Expr>, LispErr> {
 /// blisp::typing(&exprs).unwrap();
 /// ```
 pub fn typing(exprs: &LinkedList<parser::Expr>) -> Result<semantics::Context, LispErr> {
-    match semantics::exprs2context(exprs) {
-        Ok(c) => Ok(c),
-        Err(e) => {
-            let msg = format!("Typing Error: {}", e.msg);
-            Err(LispErr::new(msg, e.pos))
-        }
-    }
+    semantics::exprs2context(exprs).map_err(|e| e.into_lisp_err("Typing Error"))
 }
 
 /// evaluate an expression
@@ -191,10 +279,10 @@ extern crate std;
 
 #[cfg(test)]
 mod tests {
-    use crate::{eval, init, semantics, typing};
+    use crate::{eval, expand_macros, init, semantics, typing, FILE_ID_USER};
 
     fn eval_result(code: &str, ctx: &semantics::Context) {
-        for r in eval(code, &ctx).unwrap() {
+        for r in eval(code, ctx).unwrap() {
             println!("{} -> {}", code, r.unwrap());
         }
     }
@@ -270,6 +358,20 @@ mod tests {
         eval_result(e, &ctx);
     }
 
+    #[test]
+    fn malformed_special_form_is_an_error() {
+        // `typing` only checks effect/arity, not the shape of these special
+        // forms, so a malformed `if`/`quote`/`lambda`/`match` reaches
+        // `eval` unchecked; it must fail with an `Err`, not panic indexing
+        // a missing operand.
+        let exprs = init("").unwrap();
+        let ctx = typing(&exprs).unwrap();
+
+        assert!(eval("(if (<= 2 1) 5)", &ctx).unwrap().front().unwrap().is_err());
+        assert!(eval("(quote)", &ctx).unwrap().front().unwrap().is_err());
+        assert!(eval("(lambda (x))", &ctx).unwrap().front().unwrap().is_err());
+    }
+
     #[test]
     fn prelude() {
         let expr = "
@@ -298,28 +400,319 @@ mod tests {
         let e = "(fold + 0 '(1 2 3 4 5 6 7 8 9))";
         eval_result(e, &ctx);
 
-        let e = "(factorial 2000)";
+        // the tree-walking evaluator recurses one Rust stack frame per
+        // BLisp call (`apply` -> `eval_expr` -> `eval_list`), so this is
+        // kept well short of overflowing it; `bytecode_factorial` below
+        // drives the same function to a depth that would overflow this
+        // evaluator, through the `bytecode-vm` feature instead
+        let e = "(factorial 100)";
         eval_result(e, &ctx);
     }
 
+    #[cfg(feature = "bytecode-vm")]
     #[test]
-    fn callback() {
+    fn bytecode_factorial() {
         let expr = "
-(export callback (x y z) (IO (-> (Int Int Int) (Option Int)))
-    (call-rust x y z))";
+(export factorial (n) (Pure (-> (Int) Int))
+    (fact n 1))
+
+(defun fact (n total) (Pure (-> (Int Int) Int))
+    (if (<= n 0)
+        total
+        (fact (- n 1) (* n total))))";
         let exprs = init(expr).unwrap();
-        let mut ctx = typing(&exprs).unwrap();
+        let ctx = typing(&exprs).unwrap();
+        let prog = ctx.compile();
 
+        use crate::runtime::Value;
         use num_bigint::BigInt;
+
+        // deep enough to overflow the tree-walking evaluator (see
+        // `prelude` above), but `fact`'s self call is in tail position,
+        // so the compiler lowers it to `Instr::TailCall` and the VM runs
+        // it in constant Rust stack space
+        let result = crate::runtime::run(&prog, "factorial", vec![Value::Int(BigInt::from(2000))])
+            .unwrap();
+        match result {
+            Value::Int(n) => assert!(n > BigInt::from(0)),
+            other => panic!("expected an Int, got {}", other),
+        }
+    }
+
+    #[cfg(feature = "bytecode-vm")]
+    #[test]
+    fn bytecode_match() {
+        let expr = "
+(export is-empty (x) (Pure (-> ('(Int)) Bool))
+    (match x
+        (Nil true)
+        ((Cons _ _) false)))";
+        let exprs = init(expr).unwrap();
+        let ctx = typing(&exprs).unwrap();
+        let prog = ctx.compile();
+
+        use crate::runtime::Value;
+        use alloc::string::ToString;
+        use num_bigint::BigInt;
+
+        let nil = Value::Adt("Nil".to_string(), vec![]);
+        assert!(matches!(
+            crate::runtime::run(&prog, "is-empty", vec![nil]).unwrap(),
+            Value::Bool(true)
+        ));
+
+        let cons = Value::Adt(
+            "Cons".to_string(),
+            vec![Value::Int(BigInt::from(1)), Value::Adt("Nil".to_string(), vec![])],
+        );
+        assert!(matches!(
+            crate::runtime::run(&prog, "is-empty", vec![cons]).unwrap(),
+            Value::Bool(false)
+        ));
+    }
+
+    #[test]
+    fn extern_fn() {
+        let expr = "
+(export product (x y z) (IO (-> (Int Int Int) (Option Int)))
+    (multiply x y z))";
+        let exprs = init(expr).unwrap();
+        let mut ctx = typing(&exprs).unwrap();
+
+        use crate::runtime::Value;
+        use crate::semantics::{FfiSig, FfiType};
+        use alloc::string::ToString;
         use std::boxed::Box;
-        let fun = |x: &BigInt, y: &BigInt, z: &BigInt| {
-            let n = x * y * z;
-            println!("n = {}", n);
-            Some(n)
+
+        let sig = FfiSig {
+            args: vec![FfiType::Int, FfiType::Int, FfiType::Int],
+            ret: FfiType::Option(alloc::boxed::Box::new(FfiType::Int)),
         };
-        ctx.set_callback(Box::new(fun));
+        ctx.register_extern(
+            "multiply",
+            sig,
+            Box::new(|args: &[Value]| match (&args[0], &args[1], &args[2]) {
+                (Value::Int(x), Value::Int(y), Value::Int(z)) => {
+                    let n = x * y * z;
+                    println!("n = {}", n);
+                    Ok(Value::Adt("Some".to_string(), vec![Value::Int(n)]))
+                }
+                _ => Err("expected Int arguments".to_string()),
+            }),
+        )
+        .unwrap();
+
+        let e = "(product 100 2000 30000)";
+        eval_result(e, &ctx);
+    }
 
-        let e = "(callback 100 2000 30000)";
+    #[test]
+    fn extern_requires_io() {
+        let expr = "
+(export product (x y z) (Pure (-> (Int Int Int) (Option Int)))
+    (multiply x y z))";
+        let exprs = init(expr).unwrap();
+        let mut ctx = typing(&exprs).unwrap();
+
+        use crate::runtime::Value;
+        use crate::semantics::{FfiSig, FfiType};
+        use std::boxed::Box;
+
+        let sig = FfiSig {
+            args: vec![FfiType::Int, FfiType::Int, FfiType::Int],
+            ret: FfiType::Option(alloc::boxed::Box::new(FfiType::Int)),
+        };
+        let err = ctx
+            .register_extern(
+                "multiply",
+                sig,
+                Box::new(|args: &[Value]| Ok(args[0].clone())),
+            )
+            .unwrap_err();
+        assert!(err.msg.contains("IO"));
+    }
+
+    #[test]
+    fn extern_arity_mismatch() {
+        let expr = "
+(export product (x y) (IO (-> (Int Int) (Option Int)))
+    (multiply x y))";
+        let exprs = init(expr).unwrap();
+        let mut ctx = typing(&exprs).unwrap();
+
+        use crate::runtime::Value;
+        use crate::semantics::{FfiSig, FfiType};
+        use std::boxed::Box;
+
+        let sig = FfiSig {
+            args: vec![FfiType::Int, FfiType::Int, FfiType::Int],
+            ret: FfiType::Option(alloc::boxed::Box::new(FfiType::Int)),
+        };
+        let err = ctx
+            .register_extern(
+                "multiply",
+                sig,
+                Box::new(|args: &[Value]| Ok(args[0].clone())),
+            )
+            .unwrap_err();
+        assert!(err.msg.contains("multiply"));
+        assert!(err.msg.contains("3"));
+    }
+
+    #[test]
+    fn diagnostic_render() {
+        let expr = "\n(export product (x y z) (Pure (-> (Int Int Int) (Option Int)))\n    (multiply x y z))";
+        let exprs = init(expr).unwrap();
+        let mut ctx = typing(&exprs).unwrap();
+
+        use crate::runtime::Value;
+        use crate::semantics::{FfiSig, FfiType};
+        use std::boxed::Box;
+
+        let sig = FfiSig {
+            args: vec![FfiType::Int, FfiType::Int, FfiType::Int],
+            ret: FfiType::Option(alloc::boxed::Box::new(FfiType::Int)),
+        };
+        let err = ctx
+            .register_extern(
+                "multiply",
+                sig,
+                Box::new(|args: &[Value]| Ok(args[0].clone())),
+            )
+            .unwrap_err();
+
+        let diagnostic = err.diagnostic.expect("IO violation should carry a diagnostic");
+        let rendered = diagnostic.render(&[(FILE_ID_USER, expr)]);
+        println!("{}", rendered);
+        assert!(rendered.contains("multiply"));
+        assert!(rendered.contains("declared here"));
+    }
+
+    #[test]
+    fn arity_mismatch_diagnostic() {
+        let expr = "
+(defun double (n) (Pure (-> (Int) Int))
+    (* n 2))
+
+(export run (n) (Pure (-> (Int) Int))
+    (double n n))";
+        let exprs = init(expr).unwrap();
+        let err = match typing(&exprs) {
+            Err(e) => e,
+            Ok(_) => panic!("expected an arity mismatch"),
+        };
+        assert!(err.msg.contains("double"));
+
+        let diagnostic = err.diagnostic.expect("arity mismatch should carry a diagnostic");
+        let rendered = diagnostic.render(&[(FILE_ID_USER, expr)]);
+        println!("{}", rendered);
+        assert!(rendered.contains("double"));
+        assert!(rendered.contains("declared with 1 parameter"));
+    }
+
+    #[test]
+    fn type_mismatch_diagnostic() {
+        let expr = "
+(export run () (Pure (-> () Int))
+    (+ 1 true))";
+        let exprs = init(expr).unwrap();
+        let err = match typing(&exprs) {
+            Err(e) => e,
+            Ok(_) => panic!("expected a type mismatch"),
+        };
+        assert!(err.msg.contains("Int"));
+        assert!(err.msg.contains("Bool"));
+
+        let diagnostic = err.diagnostic.expect("type mismatch should carry a diagnostic");
+        let rendered = diagnostic.render(&[(FILE_ID_USER, expr)]);
+        println!("{}", rendered);
+        assert!(rendered.contains("expects Int"));
+    }
+
+    #[test]
+    fn macro_basic() {
+        let expr = "
+(defmacro sq (x) (* x x))
+
+(export sum-of-squares (a b) (Pure (-> (Int Int) Int))
+    (+ (sq a) (sq b)))";
+        let exprs = init(expr).unwrap();
+        let exprs = expand_macros(exprs).unwrap();
+        let ctx = typing(&exprs).unwrap();
+
+        let e = "(sum-of-squares 3 4)";
         eval_result(e, &ctx);
     }
+
+    #[test]
+    fn macro_hygiene() {
+        // the macro's own `tmp` lambda parameter must not capture the
+        // identically-named `tmp` that `outer` passes in as an argument
+        let expr = "
+(defmacro capture-test (val) ((lambda (tmp) val) 999))
+
+(export outer (tmp) (Pure (-> (Int) Int))
+    (capture-test tmp))";
+        let exprs = init(expr).unwrap();
+        let exprs = expand_macros(exprs).unwrap();
+        let ctx = typing(&exprs).unwrap();
+
+        for r in eval("(outer 42)", &ctx).unwrap() {
+            assert_eq!(r.unwrap(), "42");
+        }
+    }
+
+    #[test]
+    fn context_define() {
+        let exprs = init("").unwrap();
+        let mut ctx = typing(&exprs).unwrap();
+
+        ctx.define(
+            "(export add1 (n) (Pure (-> (Int) Int))
+                (+ n 1))",
+        )
+        .unwrap();
+        eval_result("(add1 41)", &ctx);
+
+        // a later `define` sees everything merged in by an earlier one
+        ctx.define(
+            "(export add2 (n) (Pure (-> (Int) Int))
+                (add1 (add1 n)))",
+        )
+        .unwrap();
+        eval_result("(add2 40)", &ctx);
+
+        // redefinition is still rejected, same as a fresh `typing` call
+        let err = ctx
+            .define("(export add1 (n) (Pure (-> (Int) Int)) n)")
+            .unwrap_err();
+        assert!(err.msg.contains("already defined"));
+    }
+
+    #[test]
+    fn context_infer_type() {
+        let exprs = init("").unwrap();
+        let mut ctx = typing(&exprs).unwrap();
+
+        assert_eq!(ctx.infer_type("(+ 1 2)").unwrap(), "Int");
+        assert_eq!(ctx.infer_type("(<= 1 2)").unwrap(), "Bool");
+        assert_eq!(ctx.infer_type("[1 true]").unwrap(), "[Int Bool]");
+
+        ctx.define(
+            "(export half (n) (Pure (-> (Int) (Option Int)))
+                (halve n))",
+        )
+        .unwrap();
+        // the callee's *declared* return type, not the shape of whatever
+        // it happens to evaluate to
+        assert_eq!(ctx.infer_type("(half 10)").unwrap(), "(Option Int)");
+
+        // `(Some 10)` is a bare constructor application, not a call to a
+        // `defun`/`export` with a declared return type; inferring its type
+        // would mean evaluating it, which `infer_type` no longer does
+        match ctx.infer_type("(Some 10)") {
+            Err(_) => {}
+            Ok(t) => panic!("expected an error, got `{}`", t),
+        }
+    }
 }
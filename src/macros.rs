@@ -0,0 +1,315 @@
+//! Macro expansion.
+//!
+//! BLisp has no other metaprogramming facility; every control form (`if`,
+//! `match`, `lambda`) is built into [`crate::runtime`] and [`crate::semantics`]
+//! directly. [`expand`] runs between [`crate::init`] and [`crate::typing`]:
+//! it removes every top level `(defmacro name (params...) template)` form
+//! and rewrites any `(name arg...)` call of that name into `template` with
+//! `arg...` substituted for `params...`. Expansion is purely syntactic, so
+//! the effect system and type inference never see a macro, only the
+//! ordinary `export`/`defun`/`data` forms it expands to.
+//!
+//! Substitution alone is not hygienic: a template that introduces its own
+//! `lambda` parameter or `match` binding under a name the expansion site
+//! also happens to use would otherwise capture it. [`rename`] closes that
+//! gap by giving every binding occurrence inside a macro's template a
+//! fresh, globally unique name once, at `defmacro` time, before any call
+//! site is expanded.
+
+use crate::parser::Expr;
+use crate::{Pos, Span, FILE_ID_MACRO};
+use alloc::collections::{BTreeMap, LinkedList};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// how many nested macro expansions a single top level form may go through
+/// before [`expand`] rejects it as runaway; [`expand_with_limit`] lets a
+/// caller raise or lower this for a program with unusually deep (or
+/// unusually suspicious) macro nesting
+pub const DEFAULT_MAX_EXPANSION_DEPTH: usize = 64;
+
+/// a macro error
+#[derive(Debug)]
+pub struct MacroErr {
+    pub msg: String,
+    pub pos: Pos,
+}
+
+fn err<T>(msg: String, pos: Pos) -> Result<T, MacroErr> {
+    Err(MacroErr { msg, pos })
+}
+
+/// a `(defmacro name (params...) template)` definition
+struct MacroDef {
+    params: Vec<String>,
+    body: Expr,
+}
+
+static GENSYM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// a name that, in practice, no use site can collide with: fresh names are
+/// only ever produced here and handed straight to [`Expr::Id`], never
+/// parsed back in from source, so the only way a real collision could
+/// happen is a source file spelling out this exact counter-suffixed atom
+/// (e.g. `n%3`) by hand. `%` is not actually special to [`crate::parser`]
+/// — it lexes as an ordinary atom character like any other — so this
+/// relies on that coincidence being vanishingly unlikely, not on `%` being
+/// unparseable.
+fn gensym(base: &str) -> String {
+    let n = GENSYM_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{}%{}", base, n)
+}
+
+fn synthetic_span() -> Span {
+    let pos = Pos {
+        file_id: FILE_ID_MACRO,
+        line: 0,
+        column: 0,
+    };
+    Span { start: pos, end: pos }
+}
+
+/// rename every `lambda` parameter and `match` binding in `e` to a fresh
+/// name, threading the renaming decided for enclosing binders through
+/// `env`. Identifiers not bound by an enclosing `lambda`/`match` (macro
+/// parameters among them) are left untouched; [`expand`] substitutes those
+/// later, at each use site.
+fn rename(e: &Expr, env: &BTreeMap<String, String>) -> Expr {
+    match e {
+        Expr::Num(_, _) | Expr::Bool(_, _) => e.clone(),
+        Expr::Id(name, span) => match env.get(name) {
+            Some(fresh) => Expr::Id(fresh.clone(), *span),
+            None => e.clone(),
+        },
+        Expr::Tuple(v, span) => Expr::Tuple(v.iter().map(|x| rename(x, env)).collect(), *span),
+        Expr::List(v, span) => {
+            if let Some(Expr::Id(head, _)) = v.first() {
+                if head == "quote" {
+                    return e.clone();
+                }
+                if head == "lambda" {
+                    if let Some(Expr::List(params, pspan)) = v.get(1) {
+                        let mut inner = env.clone();
+                        let mut fresh_params = Vec::new();
+                        for p in params {
+                            match p {
+                                Expr::Id(name, pos) => {
+                                    let fresh = gensym(name);
+                                    inner.insert(name.clone(), fresh.clone());
+                                    fresh_params.push(Expr::Id(fresh, *pos));
+                                }
+                                _ => fresh_params.push(p.clone()),
+                            }
+                        }
+                        let mut out = vec![v[0].clone(), Expr::List(fresh_params, *pspan)];
+                        out.extend(v[2..].iter().map(|b| rename(b, &inner)));
+                        return Expr::List(out, *span);
+                    }
+                }
+                if head == "match" {
+                    let mut out = vec![v[0].clone(), rename(&v[1], env)];
+                    for clause in &v[2..] {
+                        match clause {
+                            Expr::List(cv, cspan) if cv.len() == 2 => {
+                                let mut inner = env.clone();
+                                let pat = rename_pattern(&cv[0], &mut inner);
+                                let body = rename(&cv[1], &inner);
+                                out.push(Expr::List(vec![pat, body], *cspan));
+                            }
+                            other => out.push(rename(other, env)),
+                        }
+                    }
+                    return Expr::List(out, *span);
+                }
+            }
+            Expr::List(v.iter().map(|x| rename(x, env)).collect(), *span)
+        }
+    }
+}
+
+/// rename the binding occurrences of a `match` pattern, recording each
+/// fresh name in `env` so the corresponding clause body resolves to it.
+/// Mirrors the binding rules of [`crate::runtime::match_pattern`]: `_` and
+/// an uppercase-leading identifier (a constructor tag) never bind.
+fn rename_pattern(pat: &Expr, env: &mut BTreeMap<String, String>) -> Expr {
+    match pat {
+        Expr::Id(name, _) if name == "_" || name.starts_with(|c: char| c.is_uppercase()) => {
+            pat.clone()
+        }
+        Expr::Id(name, span) => {
+            let fresh = gensym(name);
+            env.insert(name.clone(), fresh.clone());
+            Expr::Id(fresh, *span)
+        }
+        Expr::Num(_, _) | Expr::Bool(_, _) => pat.clone(),
+        Expr::Tuple(v, span) => {
+            Expr::Tuple(v.iter().map(|p| rename_pattern(p, env)).collect(), *span)
+        }
+        Expr::List(v, span) => {
+            // (Tag sub-pattern...): the tag itself never binds
+            let mut out = Vec::new();
+            for (i, sub) in v.iter().enumerate() {
+                if i == 0 {
+                    out.push(sub.clone());
+                } else {
+                    out.push(rename_pattern(sub, env));
+                }
+            }
+            Expr::List(out, *span)
+        }
+    }
+}
+
+/// splice `bindings` into `e`, rebuilding every node `e` itself contributes
+/// (as opposed to a spliced-in argument) under a synthetic [`Span`] so an
+/// error raised against the result can be traced back to a macro expansion
+/// rather than a real source location.
+fn substitute(e: &Expr, bindings: &BTreeMap<String, Expr>) -> Expr {
+    match e {
+        Expr::Id(name, _) => match bindings.get(name) {
+            Some(arg) => arg.clone(),
+            None => Expr::Id(name.clone(), synthetic_span()),
+        },
+        Expr::Num(n, _) => Expr::Num(n.clone(), synthetic_span()),
+        Expr::Bool(b, _) => Expr::Bool(*b, synthetic_span()),
+        Expr::Tuple(v, _) => {
+            Expr::Tuple(v.iter().map(|x| substitute(x, bindings)).collect(), synthetic_span())
+        }
+        Expr::List(v, _) => {
+            Expr::List(v.iter().map(|x| substitute(x, bindings)).collect(), synthetic_span())
+        }
+    }
+}
+
+fn parse_defmacro(e: &Expr) -> Result<(String, MacroDef), MacroErr> {
+    let (v, pos) = match e {
+        Expr::List(v, span) => (v, span.start),
+        _ => return err("expected a top level form".to_string(), e.pos()),
+    };
+    let name = match v.get(1) {
+        Some(Expr::Id(s, _)) => s.clone(),
+        _ => return err("expected a macro name".to_string(), pos),
+    };
+    let params = match v.get(2) {
+        Some(Expr::List(ps, _)) => {
+            let mut out = Vec::new();
+            for p in ps {
+                match p {
+                    Expr::Id(s, _) => out.push(s.clone()),
+                    _ => return err("expected an identifier in macro parameter list".to_string(), pos),
+                }
+            }
+            out
+        }
+        _ => return err("expected a macro parameter list".to_string(), pos),
+    };
+    let raw_body = match v.get(3) {
+        Some(e) => e.clone(),
+        None => return err("expected a macro template".to_string(), pos),
+    };
+
+    // hygiene: rename the template's own bindings once, here, so no use
+    // site can ever observe or capture them.
+    let body = rename(&raw_body, &BTreeMap::new());
+    Ok((name, MacroDef { params, body }))
+}
+
+fn expand_expr(
+    e: &Expr,
+    macros: &BTreeMap<String, MacroDef>,
+    depth: usize,
+    max_depth: usize,
+) -> Result<Expr, MacroErr> {
+    match e {
+        Expr::Id(_, _) | Expr::Num(_, _) | Expr::Bool(_, _) => Ok(e.clone()),
+        Expr::Tuple(v, span) => {
+            let mut out = Vec::new();
+            for x in v {
+                out.push(expand_expr(x, macros, depth, max_depth)?);
+            }
+            Ok(Expr::Tuple(out, *span))
+        }
+        Expr::List(v, span) => {
+            if v.is_empty() {
+                return Ok(e.clone());
+            }
+            if let Expr::Id(head, _) = &v[0] {
+                if head == "quote" {
+                    return Ok(e.clone());
+                }
+                if let Some(def) = macros.get(head) {
+                    if depth >= max_depth {
+                        return err(
+                            format!(
+                                "macro `{}` exceeded the expansion depth limit ({})",
+                                head, max_depth
+                            ),
+                            span.start,
+                        );
+                    }
+                    if v.len() - 1 != def.params.len() {
+                        return err(
+                            format!(
+                                "macro `{}` expects {} argument(s), found {}",
+                                head,
+                                def.params.len(),
+                                v.len() - 1
+                            ),
+                            span.start,
+                        );
+                    }
+                    let mut args = Vec::new();
+                    for a in &v[1..] {
+                        args.push(expand_expr(a, macros, depth, max_depth)?);
+                    }
+                    let bindings: BTreeMap<String, Expr> =
+                        def.params.iter().cloned().zip(args).collect();
+                    let expanded = substitute(&def.body, &bindings);
+                    return expand_expr(&expanded, macros, depth + 1, max_depth);
+                }
+            }
+            let mut out = Vec::new();
+            for x in v {
+                out.push(expand_expr(x, macros, depth, max_depth)?);
+            }
+            Ok(Expr::List(out, *span))
+        }
+    }
+}
+
+/// expand every `defmacro` in `exprs`, returning the remaining top level
+/// forms with all macro calls replaced by their expansion, rejecting any
+/// chain of nested expansions deeper than [`DEFAULT_MAX_EXPANSION_DEPTH`]
+pub fn expand(exprs: LinkedList<Expr>) -> Result<LinkedList<Expr>, MacroErr> {
+    expand_with_limit(exprs, DEFAULT_MAX_EXPANSION_DEPTH)
+}
+
+/// like [`expand`], but with a caller-chosen expansion depth limit instead
+/// of [`DEFAULT_MAX_EXPANSION_DEPTH`] — for an embedder whose macros
+/// legitimately nest deeper, or who wants to clamp it lower than the
+/// default to bound worst-case expansion time
+pub fn expand_with_limit(
+    exprs: LinkedList<Expr>,
+    max_depth: usize,
+) -> Result<LinkedList<Expr>, MacroErr> {
+    let mut macros = BTreeMap::new();
+    let mut out = LinkedList::new();
+
+    for e in exprs {
+        let is_defmacro = matches!(
+            &e,
+            Expr::List(v, _) if matches!(v.first(), Some(Expr::Id(h, _)) if h == "defmacro")
+        );
+        if is_defmacro {
+            let (name, def) = parse_defmacro(&e)?;
+            macros.insert(name, def);
+            continue;
+        }
+        out.push_back(expand_expr(&e, &macros, 0, max_depth)?);
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,288 @@
+//! Reader for BLisp source code.
+//!
+//! The parser only turns text into a tree of [`Expr`] nodes; apart from
+//! reading `'x` as `(quote x)`, it knows nothing about
+//! `export`/`defun`/`data`/`match` or any other special form. Those are
+//! interpreted later by [`crate::semantics`].
+
+use crate::{Pos, Span};
+use alloc::collections::linked_list::LinkedList;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use num_bigint::BigInt;
+
+/// a parsed s-expression
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Id(String, Span),
+    Num(BigInt, Span),
+    Bool(bool, Span),
+    List(Vec<Expr>, Span),
+    Tuple(Vec<Expr>, Span),
+}
+
+impl Expr {
+    /// position of the first token of this expression, kept for callers
+    /// that only need a single point rather than the full [`Span`]
+    pub fn pos(&self) -> Pos {
+        self.span().start
+    }
+
+    /// the full range of source this expression was parsed from
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Id(_, s) => *s,
+            Expr::Num(_, s) => *s,
+            Expr::Bool(_, s) => *s,
+            Expr::List(_, s) => *s,
+            Expr::Tuple(_, s) => *s,
+        }
+    }
+}
+
+/// a syntax error
+#[derive(Debug)]
+pub struct ParseErr {
+    pub msg: String,
+    pub pos: Pos,
+}
+
+fn err<T>(msg: String, pos: Pos) -> Result<T, ParseErr> {
+    Err(ParseErr { msg, pos })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokKind {
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Quote,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Punct(TokKind),
+    Atom(String),
+}
+
+struct Token {
+    tok: Tok,
+    start: Pos,
+    end: Pos,
+}
+
+/// BLisp source reader producing a list of top-level expressions
+pub struct Parser<'a> {
+    code: &'a str,
+    file_id: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(code: &'a str, file_id: usize) -> Parser<'a> {
+        Parser { code, file_id }
+    }
+
+    fn pos(&self, line: usize, column: usize) -> Pos {
+        Pos {
+            file_id: self.file_id,
+            line,
+            column,
+        }
+    }
+
+    fn lex(&self) -> Result<Vec<Token>, ParseErr> {
+        let mut toks = Vec::new();
+        let mut line = 0;
+        let mut column = 0;
+        let chars: Vec<char> = self.code.chars().collect();
+        let mut i = 0;
+
+        macro_rules! punct {
+            ($kind:expr) => {{
+                let start = self.pos(line, column);
+                column += 1;
+                i += 1;
+                toks.push(Token {
+                    tok: Tok::Punct($kind),
+                    start,
+                    end: self.pos(line, column),
+                });
+            }};
+        }
+
+        while i < chars.len() {
+            let c = chars[i];
+            match c {
+                '\n' => {
+                    line += 1;
+                    column = 0;
+                    i += 1;
+                }
+                ' ' | '\t' | '\r' => {
+                    column += 1;
+                    i += 1;
+                }
+                ';' => {
+                    while i < chars.len() && chars[i] != '\n' {
+                        i += 1;
+                    }
+                }
+                '(' => punct!(TokKind::LParen),
+                ')' => punct!(TokKind::RParen),
+                '[' => punct!(TokKind::LBracket),
+                ']' => punct!(TokKind::RBracket),
+                '\'' => punct!(TokKind::Quote),
+                _ => {
+                    let start = i;
+                    let start_pos = self.pos(line, column);
+                    while i < chars.len()
+                        && !matches!(chars[i], '(' | ')' | '[' | ']' | ' ' | '\t' | '\r' | '\n' | ';')
+                    {
+                        i += 1;
+                        column += 1;
+                    }
+                    let atom: String = chars[start..i].iter().collect();
+                    toks.push(Token {
+                        tok: Tok::Atom(atom),
+                        start: start_pos,
+                        end: self.pos(line, column),
+                    });
+                }
+            }
+        }
+
+        Ok(toks)
+    }
+
+    /// parse the whole source into a list of top-level expressions
+    pub fn parse(&mut self) -> Result<LinkedList<Expr>, ParseErr> {
+        let toks = self.lex()?;
+        let mut pos = 0;
+        let mut exprs = LinkedList::new();
+        while pos < toks.len() {
+            let (e, next) = self.parse_expr(&toks, pos)?;
+            exprs.push_back(e);
+            pos = next;
+        }
+        Ok(exprs)
+    }
+
+    fn parse_expr(&self, toks: &[Token], pos: usize) -> Result<(Expr, usize), ParseErr> {
+        let tok = toks.get(pos).ok_or_else(|| ParseErr {
+            msg: "unexpected end of input".to_string(),
+            pos: self.pos(0, 0),
+        })?;
+
+        match &tok.tok {
+            Tok::Punct(TokKind::Quote) => {
+                let (inner, next) = self.parse_expr(toks, pos + 1)?;
+                let inner_end = inner.span().end;
+                let quote_span = Span {
+                    start: tok.start,
+                    end: tok.end,
+                };
+                let quote = Expr::Id("quote".to_string(), quote_span);
+                let span = Span {
+                    start: tok.start,
+                    end: inner_end,
+                };
+                Ok((Expr::List(vec![quote, inner], span), next))
+            }
+            Tok::Punct(TokKind::LParen) => {
+                self.parse_seq(toks, pos + 1, TokKind::RParen, tok.start, true)
+            }
+            Tok::Punct(TokKind::LBracket) => {
+                self.parse_seq(toks, pos + 1, TokKind::RBracket, tok.start, false)
+            }
+            Tok::Punct(TokKind::RParen) | Tok::Punct(TokKind::RBracket) => {
+                err("unexpected close paren".to_string(), tok.start)
+            }
+            Tok::Atom(a) => {
+                let span = Span {
+                    start: tok.start,
+                    end: tok.end,
+                };
+                self.parse_atom(a, span).map(|e| (e, pos + 1))
+            }
+        }
+    }
+
+    fn parse_seq(
+        &self,
+        toks: &[Token],
+        mut pos: usize,
+        close: TokKind,
+        open_pos: Pos,
+        is_list: bool,
+    ) -> Result<(Expr, usize), ParseErr> {
+        let mut elems = Vec::new();
+        let close_end;
+        loop {
+            match toks.get(pos) {
+                None => {
+                    return err("unclosed parenthesis".to_string(), open_pos);
+                }
+                Some(t) if t.tok == Tok::Punct(close) => {
+                    close_end = t.end;
+                    pos += 1;
+                    break;
+                }
+                _ => {
+                    let (e, next) = self.parse_expr(toks, pos)?;
+                    elems.push(e);
+                    pos = next;
+                }
+            }
+        }
+        let span = Span {
+            start: open_pos,
+            end: close_end,
+        };
+        let e = if is_list {
+            Expr::List(elems, span)
+        } else {
+            Expr::Tuple(elems, span)
+        };
+        Ok((e, pos))
+    }
+
+    fn parse_atom(&self, atom: &str, span: Span) -> Result<Expr, ParseErr> {
+        if atom == "true" {
+            return Ok(Expr::Bool(true, span));
+        }
+        if atom == "false" {
+            return Ok(Expr::Bool(false, span));
+        }
+
+        let (radix, digits) = if let Some(d) = atom.strip_prefix("0x") {
+            (16, d)
+        } else if let Some(d) = atom.strip_prefix("0b") {
+            (2, d)
+        } else if let Some(d) = atom.strip_prefix("0o") {
+            (8, d)
+        } else {
+            (10, atom)
+        };
+
+        if radix != 10 {
+            return match BigInt::parse_bytes(digits.as_bytes(), radix) {
+                Some(n) => Ok(Expr::Num(n, span)),
+                None => err(format!("invalid numeric literal: {}", atom), span.start),
+            };
+        }
+
+        let looks_numeric = atom.starts_with(|c: char| c.is_ascii_digit())
+            || (atom.starts_with('-') && atom.len() > 1 && atom.as_bytes()[1].is_ascii_digit());
+        if looks_numeric {
+            return match BigInt::parse_bytes(atom.as_bytes(), 10) {
+                Some(n) => Ok(Expr::Num(n, span)),
+                None => err(format!("invalid numeric literal: {}", atom), span.start),
+            };
+        }
+
+        Ok(Expr::Id(atom.to_string(), span))
+    }
+}
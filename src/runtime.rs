@@ -0,0 +1,1022 @@
+//! Evaluation of BLisp expressions against a [`crate::semantics::Context`].
+//!
+//! [`eval`] tree-walks the parsed and checked `Expr`, re-evaluating it from
+//! scratch on every call. This is simple and it is what every exported
+//! function runs through today, but it means repeated calls (e.g. a tight
+//! loop driving `(factorial 2000)`) re-walk the same AST nodes and every
+//! recursive call consumes a Rust stack frame.
+//!
+//! The `bytecode-vm` feature adds an alternate backend
+//! ([`compiler::compile_fun`] / [`run`]) that lowers a checked function into
+//! a flat [`Chunk`] of [`Instr`]s and runs it on a small stack machine,
+//! with self tail calls compiled to [`Instr::TailCall`] so deep recursion
+//! does not grow the frame stack. It is intentionally opt-in while it only
+//! covers a subset of BLisp (see [`compiler`]).
+
+use crate::parser::{self, Expr};
+use crate::semantics::{Context, FfiType};
+use crate::LispErr;
+use alloc::collections::LinkedList;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use num_bigint::{BigInt, Sign};
+
+/// a runtime value
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(BigInt),
+    Bool(bool),
+    Tuple(Vec<Value>),
+    /// an algebraic data type value: constructor tag plus its fields.
+    /// `Nil`/`Cons`/`Some`/`None` are built in; `data` declarations add
+    /// more tags structurally, with no separate runtime representation.
+    Adt(String, Vec<Value>),
+    Closure(Rc<ClosureVal>),
+    Builtin(Builtin),
+    /// a reference to a `defun`/`export`, so a plain function name
+    /// (e.g. `+` or a user function) can be passed around as a value
+    Fun(String),
+    /// a reference to a host function registered with
+    /// [`crate::semantics::Context::register_extern`]
+    Extern(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ClosureVal {
+    params: Vec<String>,
+    body: Expr,
+    env: Rc<Env>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Add,
+    Sub,
+    Mul,
+    Le,
+    Pow,
+    Band,
+    Bor,
+    Bxor,
+    Sqrt,
+    Car,
+    Cdr,
+    Map,
+    Fold,
+}
+
+fn builtin_by_name(name: &str) -> Option<Builtin> {
+    Some(match name {
+        "+" => Builtin::Add,
+        "-" => Builtin::Sub,
+        "*" => Builtin::Mul,
+        "<=" => Builtin::Le,
+        "pow" => Builtin::Pow,
+        "band" => Builtin::Band,
+        "bor" => Builtin::Bor,
+        "bxor" => Builtin::Bxor,
+        "sqrt" => Builtin::Sqrt,
+        "car" => Builtin::Car,
+        "cdr" => Builtin::Cdr,
+        "map" => Builtin::Map,
+        "fold" => Builtin::Fold,
+        _ => return None,
+    })
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Tuple(vs) => {
+                write!(f, "[")?;
+                for (i, v) in vs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Value::Adt(tag, args) => {
+                if args.is_empty() {
+                    write!(f, "{}", tag)
+                } else {
+                    write!(f, "({}", tag)?;
+                    for a in args {
+                        write!(f, " {}", a)?;
+                    }
+                    write!(f, ")")
+                }
+            }
+            Value::Closure(_) => write!(f, "<closure>"),
+            Value::Builtin(_) => write!(f, "<builtin>"),
+            Value::Fun(name) => write!(f, "<fun {}>", name),
+            Value::Extern(name) => write!(f, "<extern {}>", name),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Env {
+    Nil,
+    Cons(String, Value, Rc<Env>),
+}
+
+fn bind(env: &Rc<Env>, name: &str, value: Value) -> Rc<Env> {
+    Rc::new(Env::Cons(name.to_string(), value, env.clone()))
+}
+
+fn lookup(env: &Rc<Env>, name: &str, ctx: &Context) -> Result<Value, String> {
+    let mut cur = env;
+    while let Env::Cons(n, v, parent) = cur.as_ref() {
+        if n == name {
+            return Ok(v.clone());
+        }
+        cur = parent;
+    }
+
+    if let Some(b) = builtin_by_name(name) {
+        return Ok(Value::Builtin(b));
+    }
+    if ctx.get_fun(name).is_some() {
+        return Ok(Value::Fun(name.to_string()));
+    }
+    if ctx.get_extern(name).is_some() {
+        return Ok(Value::Extern(name.to_string()));
+    }
+    if name.starts_with(|c: char| c.is_uppercase()) {
+        // an ADT constructor tag with no arguments yet, e.g. `Nil`/`None`;
+        // `apply` fills in the fields if this is then called with some
+        return Ok(Value::Adt(name.to_string(), Vec::new()));
+    }
+    Err(format!("unbound identifier `{}`", name))
+}
+
+/// evaluate a piece of BLisp source, one result per top level expression
+pub fn eval(code: &str, ctx: &Context) -> Result<LinkedList<Result<String, String>>, LispErr> {
+    let mut ps = parser::Parser::new(code, crate::FILE_ID_EVAL);
+    let exprs = match ps.parse() {
+        Ok(e) => e,
+        Err(e) => return Err(LispErr::new(format!("Syntax Error: {}", e.msg), e.pos)),
+    };
+
+    let env = Rc::new(Env::Nil);
+    let mut results = LinkedList::new();
+    for e in &exprs {
+        results.push_back(eval_expr(e, &env, ctx).map(|v| format!("{}", v)));
+    }
+    Ok(results)
+}
+
+/// best-effort description of the shape of `v`, mirroring `Value`'s
+/// `Display` impl but naming each value's shape instead of printing it;
+/// used in the extern argument/return type errors raised by [`apply`].
+pub(crate) fn type_of(v: &Value) -> String {
+    match v {
+        Value::Int(_) => "Int".to_string(),
+        Value::Bool(_) => "Bool".to_string(),
+        Value::Tuple(vs) => {
+            let parts: Vec<String> = vs.iter().map(type_of).collect();
+            format!("[{}]", parts.join(" "))
+        }
+        Value::Adt(tag, fields) => {
+            if fields.is_empty() {
+                tag.clone()
+            } else {
+                let parts: Vec<String> = fields.iter().map(type_of).collect();
+                format!("({} {})", tag, parts.join(" "))
+            }
+        }
+        Value::Closure(_) => "<closure>".to_string(),
+        Value::Builtin(_) => "<builtin>".to_string(),
+        Value::Fun(name) => format!("<fun {}>", name),
+        Value::Extern(name) => format!("<extern {}>", name),
+    }
+}
+
+/// does `v` have the shape [`crate::semantics::FfiSig`] declares for it?
+/// Checked at every extern call, since nothing upstream of [`apply`]
+/// type-checks a BLisp value against an [`FfiType`] ahead of time.
+fn matches_ffi_type(v: &Value, t: &FfiType) -> bool {
+    match (v, t) {
+        (Value::Int(_), FfiType::Int) => true,
+        (Value::Bool(_), FfiType::Bool) => true,
+        (Value::Tuple(vs), FfiType::Tuple(ts)) => {
+            vs.len() == ts.len() && vs.iter().zip(ts).all(|(v, t)| matches_ffi_type(v, t))
+        }
+        (Value::Adt(tag, fields), FfiType::Option(inner)) => match (tag.as_str(), fields.as_slice()) {
+            ("None", []) => true,
+            ("Some", [v]) => matches_ffi_type(v, inner),
+            _ => false,
+        },
+        (Value::Adt(tag, fields), FfiType::List(inner)) => match (tag.as_str(), fields.as_slice()) {
+            ("Nil", []) => true,
+            ("Cons", [head, tail]) => matches_ffi_type(head, inner) && matches_ffi_type(tail, t),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn eval_expr(e: &Expr, env: &Rc<Env>, ctx: &Context) -> Result<Value, String> {
+    match e {
+        Expr::Num(n, _) => Ok(Value::Int(n.clone())),
+        Expr::Bool(b, _) => Ok(Value::Bool(*b)),
+        Expr::Id(name, _) => lookup(env, name, ctx),
+        Expr::Tuple(elems, _) => Ok(Value::Tuple(
+            elems
+                .iter()
+                .map(|e| eval_expr(e, env, ctx))
+                .collect::<Result<Vec<_>, _>>()?,
+        )),
+        Expr::List(v, _) => eval_list(v, env, ctx),
+    }
+}
+
+fn eval_list(v: &[Expr], env: &Rc<Env>, ctx: &Context) -> Result<Value, String> {
+    if v.is_empty() {
+        return Err("empty application".to_string());
+    }
+
+    if let Expr::Id(head, _) = &v[0] {
+        match head.as_str() {
+            "quote" => {
+                return match v.get(1) {
+                    Some(e) => quote_to_value(e),
+                    None => Err("`quote` expects 1 argument".to_string()),
+                };
+            }
+            "if" => {
+                if v.len() != 4 {
+                    return Err("`if` expects a condition, a then-branch and an else-branch".to_string());
+                }
+                return match eval_expr(&v[1], env, ctx)? {
+                    Value::Bool(true) => eval_expr(&v[2], env, ctx),
+                    Value::Bool(false) => eval_expr(&v[3], env, ctx),
+                    _ => Err("condition of `if` must be a Bool".to_string()),
+                };
+            }
+            "lambda" => {
+                if v.len() != 3 {
+                    return Err("`lambda` expects a parameter list and a body".to_string());
+                }
+                let params = match &v[1] {
+                    Expr::List(ps, _) => ps
+                        .iter()
+                        .map(|p| match p {
+                            Expr::Id(s, _) => Ok(s.clone()),
+                            _ => Err("expected a parameter name".to_string()),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                    _ => return Err("expected a parameter list".to_string()),
+                };
+                return Ok(Value::Closure(Rc::new(ClosureVal {
+                    params,
+                    body: v[2].clone(),
+                    env: env.clone(),
+                })));
+            }
+            "match" => {
+                if v.len() < 2 {
+                    return Err("`match` expects a scrutinee and at least one arm".to_string());
+                }
+                let scrutinee = eval_expr(&v[1], env, ctx)?;
+                for clause in &v[2..] {
+                    let (pat, body) = match clause {
+                        Expr::List(cv, _) if cv.len() == 2 => (&cv[0], &cv[1]),
+                        _ => return Err("expected a (pattern body) match arm".to_string()),
+                    };
+                    let mut binds = Vec::new();
+                    if match_pattern(pat, &scrutinee, &mut binds) {
+                        let mut new_env = env.clone();
+                        for (name, value) in binds {
+                            new_env = bind(&new_env, &name, value);
+                        }
+                        return eval_expr(body, &new_env, ctx);
+                    }
+                }
+                return Err("no match arm matched the value".to_string());
+            }
+            _ => {}
+        }
+    }
+
+    let fval = eval_expr(&v[0], env, ctx)?;
+    let args = v[1..]
+        .iter()
+        .map(|e| eval_expr(e, env, ctx))
+        .collect::<Result<Vec<_>, _>>()?;
+    apply(fval, args, ctx)
+}
+
+fn apply(f: Value, args: Vec<Value>, ctx: &Context) -> Result<Value, String> {
+    match f {
+        Value::Builtin(b) => apply_builtin(b, args, ctx),
+        Value::Closure(c) => {
+            if c.params.len() != args.len() {
+                return Err("wrong number of arguments".to_string());
+            }
+            let mut env = c.env.clone();
+            for (p, a) in c.params.iter().zip(args) {
+                env = bind(&env, p, a);
+            }
+            eval_expr(&c.body, &env, ctx)
+        }
+        Value::Fun(name) => {
+            let def = ctx
+                .get_fun(&name)
+                .ok_or_else(|| format!("undefined function `{}`", name))?;
+            if def.params.len() != args.len() {
+                return Err("wrong number of arguments".to_string());
+            }
+            let mut env = Rc::new(Env::Nil);
+            for (p, a) in def.params.iter().zip(args) {
+                env = bind(&env, p, a);
+            }
+            eval_expr(&def.body, &env, ctx)
+        }
+        Value::Extern(name) => {
+            let def = ctx
+                .get_extern(&name)
+                .ok_or_else(|| format!("undefined extern `{}`", name))?;
+            if def.sig.args.len() != args.len() {
+                return Err("wrong number of arguments".to_string());
+            }
+            for (i, (arg, ty)) in args.iter().zip(&def.sig.args).enumerate() {
+                if !matches_ffi_type(arg, ty) {
+                    return Err(format!(
+                        "extern `{}` expected argument {} to be `{:?}`, found `{}`",
+                        name,
+                        i,
+                        ty,
+                        type_of(arg)
+                    ));
+                }
+            }
+            let ret = (def.f)(&args)?;
+            if !matches_ffi_type(&ret, &def.sig.ret) {
+                return Err(format!(
+                    "extern `{}` was declared to return `{:?}`, but returned `{}`",
+                    name,
+                    def.sig.ret,
+                    type_of(&ret)
+                ));
+            }
+            Ok(ret)
+        }
+        Value::Adt(tag, fields) if fields.is_empty() => Ok(Value::Adt(tag, args)),
+        _ => Err("attempt to call a non-function value".to_string()),
+    }
+}
+
+fn quote_to_value(e: &Expr) -> Result<Value, String> {
+    match e {
+        Expr::Num(n, _) => Ok(Value::Int(n.clone())),
+        Expr::Bool(b, _) => Ok(Value::Bool(*b)),
+        Expr::Id(s, _) => Ok(Value::Adt(s.clone(), Vec::new())),
+        Expr::Tuple(v, _) => Ok(Value::Tuple(
+            v.iter().map(quote_to_value).collect::<Result<_, _>>()?,
+        )),
+        Expr::List(v, _) => {
+            let mut acc = Value::Adt("Nil".to_string(), Vec::new());
+            for item in v.iter().rev() {
+                acc = Value::Adt("Cons".to_string(), vec![quote_to_value(item)?, acc]);
+            }
+            Ok(acc)
+        }
+    }
+}
+
+/// try to match `pat` (unevaluated syntax) against `value`, appending any
+/// bindings introduced by the pattern to `binds`
+fn match_pattern(pat: &Expr, value: &Value, binds: &mut Vec<(String, Value)>) -> bool {
+    match pat {
+        Expr::Id(name, _) if name == "_" => true,
+        Expr::Id(name, _) if name.starts_with(|c: char| c.is_uppercase()) => {
+            matches!(value, Value::Adt(tag, fields) if tag == name && fields.is_empty())
+        }
+        Expr::Id(name, _) => {
+            binds.push((name.clone(), value.clone()));
+            true
+        }
+        Expr::Num(n, _) => matches!(value, Value::Int(m) if m == n),
+        Expr::Bool(b, _) => matches!(value, Value::Bool(v) if v == b),
+        Expr::Tuple(pats, _) => match value {
+            Value::Tuple(vs) if vs.len() == pats.len() => pats
+                .iter()
+                .zip(vs.iter())
+                .all(|(p, v)| match_pattern(p, v, binds)),
+            _ => false,
+        },
+        Expr::List(pv, _) => {
+            let (tag, sub_pats) = match pv.first() {
+                Some(Expr::Id(t, _)) => (t.as_str(), &pv[1..]),
+                _ => return false,
+            };
+            match value {
+                Value::Adt(vtag, fields) if vtag == tag && fields.len() == sub_pats.len() => {
+                    sub_pats
+                        .iter()
+                        .zip(fields.iter())
+                        .all(|(p, v)| match_pattern(p, v, binds))
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+fn to_u32(n: &BigInt) -> Result<u32, String> {
+    let (sign, digits) = n.to_u32_digits();
+    match (sign, digits.as_slice()) {
+        (Sign::Minus, _) => Err("expected a non-negative integer".to_string()),
+        (_, []) => Ok(0),
+        (_, [d]) => Ok(*d),
+        _ => Err("integer too large".to_string()),
+    }
+}
+
+/// integer square root via Newton's method on big integers. BLisp has no
+/// complex numbers, so a negative `n` is square-rooted by magnitude
+/// rather than rejected outright, the same way `(sqrt -1)` is `1` and not
+/// an error in most integer-only Lisps.
+fn int_sqrt(n: &BigInt) -> Result<BigInt, String> {
+    let magnitude = if n.sign() == Sign::Minus { -n } else { n.clone() };
+    let n = &magnitude;
+    if n.sign() == Sign::NoSign {
+        return Ok(BigInt::from(0));
+    }
+    let one = BigInt::from(1);
+    let two = BigInt::from(2);
+    let mut x = n.clone();
+    let mut y = (&x + &one) / &two;
+    while y < x {
+        x = y.clone();
+        y = (&x + n / &x) / &two;
+    }
+    Ok(x)
+}
+
+fn as_int(v: &Value) -> Result<&BigInt, String> {
+    match v {
+        Value::Int(n) => Ok(n),
+        _ => Err("expected an Int".to_string()),
+    }
+}
+
+/// the subset of builtins that only touch `Int`/`Adt` values and so need
+/// no [`Context`] to apply; shared between the tree-walking evaluator and
+/// the bytecode VM's `CallBuiltin`
+fn apply_numeric_builtin(b: Builtin, args: &[Value]) -> Option<Result<Value, String>> {
+    Some(match b {
+        Builtin::Add | Builtin::Sub | Builtin::Mul | Builtin::Band | Builtin::Bor | Builtin::Bxor => {
+            (|| {
+                if args.len() != 2 {
+                    return Err("expected 2 arguments".to_string());
+                }
+                let x = as_int(&args[0])?;
+                let y = as_int(&args[1])?;
+                Ok(Value::Int(match b {
+                    Builtin::Add => x + y,
+                    Builtin::Sub => x - y,
+                    Builtin::Mul => x * y,
+                    Builtin::Band => x & y,
+                    Builtin::Bor => x | y,
+                    Builtin::Bxor => x ^ y,
+                    _ => unreachable!(),
+                }))
+            })()
+        }
+        Builtin::Le => (|| {
+            if args.len() != 2 {
+                return Err("expected 2 arguments".to_string());
+            }
+            Ok(Value::Bool(as_int(&args[0])? <= as_int(&args[1])?))
+        })(),
+        Builtin::Pow => (|| {
+            if args.len() != 2 {
+                return Err("expected 2 arguments".to_string());
+            }
+            let exp = to_u32(as_int(&args[1])?)?;
+            Ok(Value::Int(as_int(&args[0])?.pow(exp)))
+        })(),
+        Builtin::Sqrt => (|| {
+            if args.len() != 1 {
+                return Err("expected 1 argument".to_string());
+            }
+            Ok(Value::Int(int_sqrt(as_int(&args[0])?)?))
+        })(),
+        Builtin::Car => (|| {
+            if args.len() != 1 {
+                return Err("expected 1 argument".to_string());
+            }
+            match &args[0] {
+                Value::Adt(tag, fields) if tag == "Cons" => Ok(fields[0].clone()),
+                _ => Err("`car` of an empty list".to_string()),
+            }
+        })(),
+        Builtin::Cdr => (|| {
+            if args.len() != 1 {
+                return Err("expected 1 argument".to_string());
+            }
+            match &args[0] {
+                Value::Adt(tag, fields) if tag == "Cons" => Ok(fields[1].clone()),
+                _ => Err("`cdr` of an empty list".to_string()),
+            }
+        })(),
+        Builtin::Map | Builtin::Fold => return None,
+    })
+}
+
+fn apply_builtin(b: Builtin, args: Vec<Value>, ctx: &Context) -> Result<Value, String> {
+    if let Some(r) = apply_numeric_builtin(b, &args) {
+        return r;
+    }
+
+    match b {
+        Builtin::Add
+        | Builtin::Sub
+        | Builtin::Mul
+        | Builtin::Band
+        | Builtin::Bor
+        | Builtin::Bxor
+        | Builtin::Le
+        | Builtin::Pow
+        | Builtin::Sqrt
+        | Builtin::Car
+        | Builtin::Cdr => unreachable!("handled by apply_numeric_builtin above"),
+        Builtin::Map => {
+            if args.len() != 2 {
+                return Err("expected 2 arguments".to_string());
+            }
+            let f = args[0].clone();
+            let mut items = Vec::new();
+            let mut cur = args[1].clone();
+            loop {
+                match cur {
+                    Value::Adt(tag, fields) if tag == "Cons" => {
+                        items.push(apply(f.clone(), vec![fields[0].clone()], ctx)?);
+                        cur = fields[1].clone();
+                    }
+                    Value::Adt(tag, _) if tag == "Nil" => break,
+                    _ => return Err("`map` expects a list".to_string()),
+                }
+            }
+            let mut acc = Value::Adt("Nil".to_string(), Vec::new());
+            for item in items.into_iter().rev() {
+                acc = Value::Adt("Cons".to_string(), vec![item, acc]);
+            }
+            Ok(acc)
+        }
+        Builtin::Fold => {
+            if args.len() != 3 {
+                return Err("expected 3 arguments".to_string());
+            }
+            let f = args[0].clone();
+            let mut acc = args[1].clone();
+            let mut cur = args[2].clone();
+            loop {
+                match cur {
+                    Value::Adt(tag, fields) if tag == "Cons" => {
+                        acc = apply(f.clone(), vec![acc, fields[0].clone()], ctx)?;
+                        cur = fields[1].clone();
+                    }
+                    Value::Adt(tag, _) if tag == "Nil" => break,
+                    _ => return Err("`fold` expects a list".to_string()),
+                }
+            }
+            Ok(acc)
+        }
+    }
+}
+
+/// a single bytecode instruction executed by the [`run`] VM
+#[derive(Debug, Clone)]
+#[cfg(feature = "bytecode-vm")]
+pub enum Instr {
+    PushConst(usize),
+    PushLocal(usize),
+    Call(usize, usize),
+    TailCall(usize, usize),
+    /// pop `argc` operands and apply a numeric builtin (`+`, `<=`, `sqrt`,
+    /// ...); builtins that take a function argument (`map`, `fold`) are
+    /// not supported here and the compiler traps on them instead
+    CallBuiltin(Builtin, usize),
+    /// building closures over the bytecode VM is not implemented yet; kept
+    /// as a variant so [`compiler`] has somewhere to grow into
+    MakeClosure(usize),
+    Jump(usize),
+    JumpIfFalse(usize),
+    /// if the top of the stack is an `Adt` with the given tag, pop it and
+    /// push its fields; otherwise jump to the given instruction
+    MatchTag(String, usize),
+    /// pop the top of the stack, discard the `n` values now on top of it,
+    /// then push the popped value back; used after a `match` arm to drop
+    /// the scrutinee/bound fields once the arm's body has produced its
+    /// result, so the stack depth after a `match` is the same regardless
+    /// of which arm ran
+    Slide(usize),
+    Return,
+    /// a construct the compiler does not lower yet; executing one fails
+    /// with the given message instead of panicking
+    Trap(String),
+}
+
+/// one function's worth of compiled code
+#[cfg(feature = "bytecode-vm")]
+pub struct Chunk {
+    pub code: Vec<Instr>,
+    pub consts: Vec<Value>,
+}
+
+#[cfg(feature = "bytecode-vm")]
+struct Frame {
+    chunk_id: usize,
+    ip: usize,
+    base: usize,
+}
+
+/// run a compiled [`crate::semantics::Program`] starting at `entry`
+#[cfg(feature = "bytecode-vm")]
+pub fn run(
+    prog: &crate::semantics::Program,
+    entry: &str,
+    args: Vec<Value>,
+) -> Result<Value, String> {
+    let entry_id = *prog
+        .names
+        .get(entry)
+        .ok_or_else(|| format!("no such function `{}`", entry))?;
+    let mut stack: Vec<Value> = args;
+    let mut frames = vec![Frame {
+        chunk_id: entry_id,
+        ip: 0,
+        base: 0,
+    }];
+
+    loop {
+        let (chunk_id, ip, base) = {
+            let f = frames.last().unwrap();
+            (f.chunk_id, f.ip, f.base)
+        };
+        let chunk = &prog.chunks[chunk_id];
+        let instr = chunk
+            .code
+            .get(ip)
+            .cloned()
+            .ok_or_else(|| "chunk fell off the end without `Return`".to_string())?;
+        frames.last_mut().unwrap().ip = ip + 1;
+
+        match instr {
+            Instr::PushConst(i) => stack.push(chunk.consts[i].clone()),
+            Instr::PushLocal(slot) => stack.push(stack[base + slot].clone()),
+            Instr::Jump(target) => frames.last_mut().unwrap().ip = target,
+            Instr::JumpIfFalse(target) => {
+                match stack.pop().ok_or_else(|| "stack underflow".to_string())? {
+                    Value::Bool(false) => frames.last_mut().unwrap().ip = target,
+                    Value::Bool(true) => {}
+                    _ => return Err("condition must be a Bool".to_string()),
+                }
+            }
+            Instr::MatchTag(tag, else_ip) => {
+                let top = stack.last().cloned().ok_or_else(|| "stack underflow".to_string())?;
+                match top {
+                    Value::Adt(t, fields) if t == tag => {
+                        stack.pop();
+                        for field in fields {
+                            stack.push(field);
+                        }
+                    }
+                    _ => frames.last_mut().unwrap().ip = else_ip,
+                }
+            }
+            Instr::Slide(n) => {
+                let top = stack.pop().ok_or_else(|| "stack underflow".to_string())?;
+                let new_len = stack
+                    .len()
+                    .checked_sub(n)
+                    .ok_or_else(|| "stack underflow".to_string())?;
+                stack.truncate(new_len);
+                stack.push(top);
+            }
+            Instr::Call(fn_id, argc) => {
+                let call_base = stack.len() - argc;
+                frames.push(Frame {
+                    chunk_id: fn_id,
+                    ip: 0,
+                    base: call_base,
+                });
+            }
+            Instr::TailCall(fn_id, argc) => {
+                let new_args = stack.split_off(stack.len() - argc);
+                stack.truncate(base);
+                stack.extend(new_args);
+                let f = frames.last_mut().unwrap();
+                f.chunk_id = fn_id;
+                f.ip = 0;
+            }
+            Instr::Return => {
+                let v = stack.pop().ok_or_else(|| "stack underflow".to_string())?;
+                let done = frames.pop().unwrap();
+                stack.truncate(done.base);
+                if frames.is_empty() {
+                    return Ok(v);
+                }
+                stack.push(v);
+            }
+            Instr::CallBuiltin(b, argc) => {
+                let call_args = stack.split_off(stack.len() - argc);
+                let r = apply_numeric_builtin(b, &call_args)
+                    .unwrap_or_else(|| Err(format!("builtin {:?} needs a Context", b)))?;
+                stack.push(r);
+            }
+            Instr::MakeClosure(_) => {
+                return Err("closures are not yet supported by the bytecode VM".to_string());
+            }
+            Instr::Trap(msg) => return Err(msg),
+        }
+    }
+}
+
+/// lowers checked `defun`/`export` bodies into [`Chunk`]s.
+///
+/// Only the subset of BLisp needed for first-order, directly recursive
+/// numeric functions (the `factorial`-style accumulator pattern) is
+/// actually lowered; anything else compiles to an [`Instr::Trap`] so a
+/// caller gets a runtime error instead of wrong results. Widening this
+/// (closures, general pattern matching, tuples) is follow-up work.
+#[cfg(feature = "bytecode-vm")]
+pub(crate) mod compiler {
+    use super::{Builtin, Chunk, Instr, Value};
+    use crate::parser::Expr;
+    use crate::semantics::FuncDef;
+    use alloc::collections::BTreeMap;
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    struct Compiler<'a> {
+        locals: Vec<String>,
+        consts: Vec<Value>,
+        code: Vec<Instr>,
+        names: &'a BTreeMap<String, usize>,
+    }
+
+    impl<'a> Compiler<'a> {
+        fn push_const(&mut self, v: Value) -> usize {
+            self.consts.push(v);
+            self.consts.len() - 1
+        }
+
+        /// compile `e`; `tail` indicates `e` is in tail position of the
+        /// enclosing function, so a trailing self/sibling call becomes a
+        /// `TailCall` instead of a `Call`
+        fn compile_expr(&mut self, e: &Expr, tail: bool) {
+            match e {
+                Expr::Num(n, _) => {
+                    let idx = self.push_const(Value::Int(n.clone()));
+                    self.code.push(Instr::PushConst(idx));
+                }
+                Expr::Bool(b, _) => {
+                    let idx = self.push_const(Value::Bool(*b));
+                    self.code.push(Instr::PushConst(idx));
+                }
+                Expr::Id(name, _) => {
+                    if let Some(slot) = self.locals.iter().rposition(|l| l == name) {
+                        self.code.push(Instr::PushLocal(slot));
+                    } else {
+                        self.code.push(Instr::Trap(format!(
+                            "bytecode-vm: free variable `{}` is not supported",
+                            name
+                        )));
+                    }
+                }
+                Expr::Tuple(_, _) => {
+                    self.code.push(Instr::Trap(
+                        "bytecode-vm: tuples are not supported yet".into(),
+                    ));
+                }
+                Expr::List(v, _) => self.compile_list(v, tail),
+            }
+        }
+
+        fn compile_list(&mut self, v: &[Expr], tail: bool) {
+            if v.is_empty() {
+                self.code.push(Instr::Trap("empty application".into()));
+                return;
+            }
+
+            if let Expr::Id(head, _) = &v[0] {
+                if head == "if" && v.len() == 4 {
+                    self.compile_expr(&v[1], false);
+                    let jf = self.code.len();
+                    self.code.push(Instr::JumpIfFalse(0));
+                    self.compile_expr(&v[2], tail);
+                    let j = self.code.len();
+                    self.code.push(Instr::Jump(0));
+                    let else_start = self.code.len();
+                    self.compile_expr(&v[3], tail);
+                    let end = self.code.len();
+                    self.code[jf] = Instr::JumpIfFalse(else_start);
+                    self.code[j] = Instr::Jump(end);
+                    return;
+                }
+
+                if head == "match" && v.len() >= 3 {
+                    self.compile_match(&v[1], &v[2..], tail);
+                    return;
+                }
+
+                if let Some(b) = super::builtin_by_name(head) {
+                    let argc = v.len() - 1;
+                    if matches!(b, Builtin::Map | Builtin::Fold) {
+                        self.code.push(Instr::Trap(format!(
+                            "bytecode-vm: builtin {:?} takes a function argument, not supported yet",
+                            b
+                        )));
+                        return;
+                    }
+                    for a in &v[1..] {
+                        self.compile_expr(a, false);
+                        // keep `locals` aligned with the stack: an evaluated
+                        // operand holds a slot until the call consumes it
+                        self.locals.push("%operand".into());
+                    }
+                    let depth = self.locals.len() - argc;
+                    self.locals.truncate(depth);
+                    self.code.push(Instr::CallBuiltin(b, argc));
+                    return;
+                }
+
+                if let Some(&fn_id) = self.names.get(head) {
+                    for a in &v[1..] {
+                        self.compile_expr(a, false);
+                        self.locals.push("%operand".into());
+                    }
+                    let argc = v.len() - 1;
+                    let depth = self.locals.len() - argc;
+                    self.locals.truncate(depth);
+                    self.code.push(if tail {
+                        Instr::TailCall(fn_id, argc)
+                    } else {
+                        Instr::Call(fn_id, argc)
+                    });
+                    return;
+                }
+            }
+
+            self.code.push(Instr::Trap(
+                "bytecode-vm: only calls to known numeric functions are supported".into(),
+            ));
+        }
+
+        /// compile a `match`: the scrutinee is evaluated once and tested
+        /// against each clause with [`Instr::MatchTag`] (which, on a
+        /// mismatch, leaves the value on the stack for the next clause to
+        /// test). Only flat patterns are lowered: a zero-arg tag (`Nil`),
+        /// a tag applied to `_`/plain bindings (`(Cons n rest)`), `_`, or a
+        /// plain binding that matches anything; a nested pattern traps.
+        /// Every arm is normalized back to the same stack depth with
+        /// [`Instr::Slide`] so the code compiled after the `match` sees a
+        /// stack depth that does not depend on which arm ran.
+        fn compile_match(&mut self, scrutinee: &Expr, clauses: &[Expr], tail: bool) {
+            self.compile_expr(scrutinee, false);
+            let scrut_slot = self.locals.len();
+            self.locals.push("%scrutinee".into());
+            let base_locals = self.locals.clone();
+
+            let mut end_jumps = Vec::new();
+            let mut exhaustive = false;
+            for clause in clauses {
+                let (pat, body) = match clause {
+                    Expr::List(cv, _) if cv.len() == 2 => (&cv[0], &cv[1]),
+                    _ => {
+                        self.code.push(Instr::Trap(
+                            "bytecode-vm: expected a (pattern body) match arm".into(),
+                        ));
+                        exhaustive = true;
+                        break;
+                    }
+                };
+
+                // `names`: `None` if this clause always matches (`_` or a
+                // plain binding of the whole scrutinee); `Some(fields)` if
+                // it only matches a given tag, binding each flat sub-field
+                let (tag, names): (Option<String>, Vec<Option<String>>) = match pat {
+                    Expr::Id(name, _) if name == "_" => (None, Vec::new()),
+                    Expr::Id(name, _) if !name.starts_with(|c: char| c.is_uppercase()) => {
+                        (None, vec![Some(name.clone())])
+                    }
+                    Expr::Id(tag, _) => (Some(tag.clone()), Vec::new()),
+                    Expr::List(pv, _) => match pv.split_first() {
+                        Some((Expr::Id(tag, _), subpats))
+                            if subpats.iter().all(|p| {
+                                matches!(p, Expr::Id(n, _) if n == "_" || !n.starts_with(|c: char| c.is_uppercase()))
+                            }) =>
+                        {
+                            let names = subpats
+                                .iter()
+                                .map(|p| match p {
+                                    Expr::Id(n, _) if n == "_" => None,
+                                    Expr::Id(n, _) => Some(n.clone()),
+                                    _ => unreachable!(),
+                                })
+                                .collect();
+                            (Some(tag.clone()), names)
+                        }
+                        _ => {
+                            self.code.push(Instr::Trap(
+                                "bytecode-vm: only a flat `(Tag binding...)` pattern is supported"
+                                    .into(),
+                            ));
+                            exhaustive = true;
+                            break;
+                        }
+                    },
+                    _ => {
+                        self.code.push(Instr::Trap(
+                            "bytecode-vm: unsupported match pattern".into(),
+                        ));
+                        exhaustive = true;
+                        break;
+                    }
+                };
+
+                let jf = tag.as_ref().map(|_| {
+                    let jf = self.code.len();
+                    self.code.push(Instr::MatchTag(String::new(), 0));
+                    jf
+                });
+
+                self.locals = base_locals.clone();
+                self.locals.truncate(scrut_slot);
+                for name in &names {
+                    self.locals.push(name.clone().unwrap_or_else(|| "_".into()));
+                }
+                // how many values besides the arm's result sit on the
+                // stack once its body finishes: a tested tag replaced the
+                // scrutinee with its fields (`names.len()`, maybe 0); an
+                // untested `_`/binding pattern never popped the scrutinee,
+                // so one value (bound or not) is always left behind
+                let extra = if tag.is_some() { names.len() } else { 1 };
+
+                self.compile_expr(body, tail);
+                self.code.push(Instr::Slide(extra));
+
+                match jf {
+                    None => {
+                        // this clause always matches: unreachable clauses
+                        // after it would be dead code, so stop here
+                        exhaustive = true;
+                        break;
+                    }
+                    Some(jf) => {
+                        let j = self.code.len();
+                        self.code.push(Instr::Jump(0));
+                        end_jumps.push(j);
+                        let else_start = self.code.len();
+                        self.code[jf] = Instr::MatchTag(tag.unwrap(), else_start);
+                    }
+                }
+            }
+
+            if !exhaustive {
+                self.code.push(Instr::Trap(
+                    "no match arm matched the value".into(),
+                ));
+            }
+            let end = self.code.len();
+            for j in end_jumps {
+                self.code[j] = Instr::Jump(end);
+            }
+            // the match as a whole behaves like any other expression: it
+            // leaves exactly one (untracked, transient) value on the
+            // stack, so `self.locals` goes back to exactly how it stood
+            // before the scrutinee was evaluated
+            self.locals = base_locals;
+            self.locals.truncate(scrut_slot);
+        }
+    }
+
+    pub(crate) fn compile_fun(def: &FuncDef, names: &BTreeMap<String, usize>) -> Chunk {
+        let mut c = Compiler {
+            locals: def.params.clone(),
+            consts: Vec::new(),
+            code: Vec::new(),
+            names,
+        };
+        c.compile_expr(&def.body, true);
+        c.code.push(Instr::Return);
+        Chunk {
+            code: c.code,
+            consts: c.consts,
+        }
+    }
+}
@@ -0,0 +1,635 @@
+//! Name resolution and (currently minimal) type checking.
+//!
+//! `exprs2context` walks the top level forms produced by [`crate::parser`]
+//! (`export`, `defun`, `data`) and builds a [`Context`] that the rest of
+//! the crate evaluates against. There is no unification or inference over
+//! the whole program; `check_types` only catches a builtin call whose
+//! argument's type [`static_type_of`] can classify without evaluating it,
+//! such as `(+ 1 true)`. Anything it cannot classify is left for
+//! `runtime` to reject at evaluation time instead.
+
+use crate::diagnostics::Diagnostic;
+use crate::parser::Expr;
+#[cfg(feature = "bytecode-vm")]
+use crate::runtime::Chunk;
+use crate::runtime::Value;
+use crate::{LispErr, Pos, Span};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, LinkedList};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// effect annotation of a function, `Pure` or `IO`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    Pure,
+    IO,
+}
+
+/// a user defined (or exported) function
+#[derive(Debug, Clone)]
+pub struct FuncDef {
+    pub params: Vec<String>,
+    pub body: Expr,
+    pub effect: Effect,
+    pub exported: bool,
+    pub pos: Pos,
+    /// span of this function's effect/type annotation, e.g. `(IO ...)`;
+    /// used as the secondary label of an effect-violation [`Diagnostic`]
+    pub effect_span: Span,
+    /// the declared return type, rendered back from the `(-> (arg-types...)
+    /// ret-type)` annotation, e.g. `"(Option Int)"`; `"?"` if the
+    /// annotation was malformed in a way [`add_fun`] otherwise tolerates.
+    /// Used by [`Context::infer_type`] to report the type a call to this
+    /// function was declared to have, without evaluating it.
+    pub(crate) ret_type: String,
+    /// every identifier this function applies as a function, together with
+    /// the span of that call site and how many arguments it was given;
+    /// collected while checking it, and used both to retroactively enforce
+    /// the `IO` effect once a name turns out to name a registered extern,
+    /// and to catch an arity mismatch against another `defun`/`export`
+    /// once every function in the program is known
+    pub(crate) calls: Vec<(String, Span, usize)>,
+}
+
+/// a semantic error
+///
+/// `diagnostic` is set for errors that are clearer as more than one labeled
+/// span (e.g. an arity mismatch wants to point at both the call site and
+/// the callee's declared parameter list); [`err`] leaves it unset for the
+/// common single-span case.
+#[derive(Debug)]
+pub struct SemErr {
+    pub msg: String,
+    pub pos: Pos,
+    pub diagnostic: Option<Box<Diagnostic>>,
+}
+
+fn err<T>(msg: String, pos: Pos) -> Result<T, SemErr> {
+    Err(SemErr { msg, pos, diagnostic: None })
+}
+
+fn err_diagnostic<T>(d: Diagnostic) -> Result<T, SemErr> {
+    Err(SemErr {
+        msg: d.msg.clone(),
+        pos: d.primary.start,
+        diagnostic: Some(Box::new(d)),
+    })
+}
+
+impl SemErr {
+    /// turn this into the crate-wide [`LispErr`], prefixing `msg` and
+    /// carrying the [`Diagnostic`] through if one was attached, so a
+    /// multi-label error (e.g. an arity mismatch) survives past
+    /// [`exprs2context`]/[`Context::define`] instead of being flattened to
+    /// its single-span summary
+    pub(crate) fn into_lisp_err(self, prefix: &str) -> LispErr {
+        LispErr {
+            msg: format!("{}: {}", prefix, self.msg),
+            pos: self.pos,
+            diagnostic: self.diagnostic,
+        }
+    }
+}
+
+/// a BLisp-level type, as used to describe the arguments and return value
+/// of a registered extern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FfiType {
+    Int,
+    Bool,
+    Tuple(Vec<FfiType>),
+    Option(Box<FfiType>),
+    List(Box<FfiType>),
+}
+
+/// the BLisp-visible signature of a host function registered with
+/// [`Context::register_extern`]
+#[derive(Debug, Clone)]
+pub struct FfiSig {
+    pub args: Vec<FfiType>,
+    pub ret: FfiType,
+}
+
+/// a host function, boxed so [`Context::register_extern`] and
+/// [`ExternDef`] can each name the type without repeating the trait object
+pub(crate) type ExternFn = Box<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+/// a host function bound into BLisp under a name
+pub(crate) struct ExternDef {
+    pub(crate) sig: FfiSig,
+    pub(crate) f: ExternFn,
+}
+
+/// the environment a program is checked and evaluated against
+pub struct Context {
+    pub(crate) funs: BTreeMap<String, FuncDef>,
+    pub(crate) externs: BTreeMap<String, ExternDef>,
+}
+
+impl Context {
+    fn new() -> Context {
+        Context {
+            funs: BTreeMap::new(),
+            externs: BTreeMap::new(),
+        }
+    }
+
+    /// register a host function under `name`, so that any BLisp call
+    /// `(name arg...)` marshals its evaluated arguments into `&[Value]`
+    /// and runs `f`.
+    ///
+    /// `sig` is checked against every call site already collected while
+    /// building this `Context`: the arity must match, and every function
+    /// that calls `name` must be declared `IO`, since calling out to the
+    /// host is a side effect. This replaces the single hard-coded
+    /// `call-rust` form with a registry so embedders can expose as many
+    /// host functions, with whatever arity, as they need.
+    pub fn register_extern(&mut self, name: &str, sig: FfiSig, f: ExternFn) -> Result<(), LispErr> {
+        for def in self.funs.values() {
+            for (call_name, call_span, argc) in &def.calls {
+                if call_name != name {
+                    continue;
+                }
+                if def.effect != Effect::IO {
+                    let diagnostic = Diagnostic::new(
+                        "E0extern-io",
+                        format!("extern `{}` may only be called from an `IO` function", name),
+                        *call_span,
+                    )
+                    .with_label(def.effect_span, "declared here".to_string())
+                    .with_help(format!("mark this function's effect as `IO` to call `{}`", name));
+                    return Err(LispErr::from_diagnostic(diagnostic));
+                }
+                if sig.args.len() != *argc {
+                    let diagnostic = Diagnostic::new(
+                        "E0arity-mismatch",
+                        format!("`{}` expects {} argument(s), found {}", name, sig.args.len(), argc),
+                        *call_span,
+                    )
+                    .with_help(format!(
+                        "the extern `{}` is registered with {} argument(s)",
+                        name,
+                        sig.args.len()
+                    ));
+                    return Err(LispErr::from_diagnostic(diagnostic));
+                }
+            }
+        }
+
+        self.externs.insert(name.to_string(), ExternDef { sig, f });
+        Ok(())
+    }
+
+    pub(crate) fn get_fun(&self, name: &str) -> Option<&FuncDef> {
+        self.funs.get(name)
+    }
+
+    pub(crate) fn get_extern(&self, name: &str) -> Option<&ExternDef> {
+        self.externs.get(name)
+    }
+
+    /// parse `code` as a fragment of zero or more `export`/`defun`/`data`
+    /// forms, type-check each against this `Context` as it already stands,
+    /// and merge it in — detecting redefinition and effect/extern
+    /// conflicts exactly as [`crate::typing`] would, but without
+    /// re-parsing the prelude or anything `define`d before it. The arity
+    /// and builtin-argument checks are then re-run over every function in
+    /// this `Context`, so an earlier call to a name this fragment defines
+    /// is checked too. This is what lets a REPL grow its environment one
+    /// form at a time instead of re-running [`crate::typing`] over the
+    /// source of everything it has seen so far.
+    pub fn define(&mut self, code: &str) -> Result<(), LispErr> {
+        let mut ps = crate::parser::Parser::new(code, crate::FILE_ID_REPL);
+        let exprs = ps
+            .parse()
+            .map_err(|e| LispErr::new(format!("Syntax Error: {}", e.msg), e.pos))?;
+
+        for e in &exprs {
+            add_top_level(self, e).map_err(|e| e.into_lisp_err("Typing Error"))?;
+        }
+        check_arities(self).map_err(|e| e.into_lisp_err("Typing Error"))?;
+        check_types(self).map_err(|e| e.into_lisp_err("Typing Error"))?;
+        Ok(())
+    }
+
+    /// infer the type of a single expression without evaluating it, so a
+    /// REPL can show it to the user before running anything with a side
+    /// effect.
+    ///
+    /// BLisp's effect system only tracks `Pure`/`IO` and arity, not a full
+    /// static type, so this is necessarily best-effort: it recognizes a
+    /// literal, and a call to a builtin or a known `defun`/`export`,
+    /// reporting the callee's *declared* return type (e.g. `(Option Int)`)
+    /// rather than a value's shape. Anything it cannot classify this way —
+    /// a bare parameter reference, `if`/`match`/`lambda`, a call through a
+    /// value rather than a name — is rejected rather than silently
+    /// evaluated.
+    pub fn infer_type(&self, expr: &str) -> Result<String, LispErr> {
+        let mut ps = crate::parser::Parser::new(expr, crate::FILE_ID_REPL);
+        let mut exprs = ps
+            .parse()
+            .map_err(|e| LispErr::new(format!("Syntax Error: {}", e.msg), e.pos))?;
+        let e = exprs.pop_front().ok_or_else(|| {
+            LispErr::new(
+                "expected an expression".to_string(),
+                Pos {
+                    file_id: crate::FILE_ID_REPL,
+                    line: 0,
+                    column: 0,
+                },
+            )
+        })?;
+        let pos = e.pos();
+        static_type_of(&e, self).ok_or_else(|| {
+            LispErr::new(
+                "cannot infer a type for this expression without evaluating it \
+                 (only literals and calls to a known `defun`/`export` or builtin are supported)"
+                    .to_string(),
+                pos,
+            )
+        })
+    }
+}
+
+/// best-effort static type of `e`, without evaluating it; see
+/// [`Context::infer_type`]
+fn static_type_of(e: &Expr, ctx: &Context) -> Option<String> {
+    match e {
+        Expr::Num(_, _) => Some("Int".to_string()),
+        Expr::Bool(_, _) => Some("Bool".to_string()),
+        Expr::Tuple(v, _) => {
+            let parts = v
+                .iter()
+                .map(|x| static_type_of(x, ctx))
+                .collect::<Option<Vec<_>>>()?;
+            Some(format!("[{}]", parts.join(" ")))
+        }
+        Expr::List(v, _) => {
+            let head = match v.first() {
+                Some(Expr::Id(name, _)) => name.as_str(),
+                _ => return None,
+            };
+            if let Some(def) = ctx.get_fun(head) {
+                return Some(def.ret_type.clone());
+            }
+            match head {
+                "+" | "-" | "*" | "pow" | "band" | "bor" | "bxor" | "sqrt" => {
+                    Some("Int".to_string())
+                }
+                "<=" => Some("Bool".to_string()),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// render a type annotation expression (e.g. `(Option Int)`, `Int`, or
+/// BLisp's `'(Int)` list-type sugar) back to source-like text, so
+/// [`Context::infer_type`] can report a real declared type instead of the
+/// shape of some value it evaluated to
+fn format_type(e: &Expr) -> String {
+    match e {
+        Expr::Id(name, _) => name.clone(),
+        Expr::Num(n, _) => n.to_string(),
+        Expr::Bool(b, _) => b.to_string(),
+        Expr::Tuple(v, _) => {
+            let parts: Vec<String> = v.iter().map(format_type).collect();
+            format!("[{}]", parts.join(" "))
+        }
+        Expr::List(v, _) => {
+            // `'(Int)` list-type sugar parses as `(quote (Int))`
+            if let [Expr::Id(q, _), inner] = v.as_slice() {
+                if q == "quote" {
+                    return format!("'{}", format_type(inner));
+                }
+            }
+            let parts: Vec<String> = v.iter().map(format_type).collect();
+            format!("({})", parts.join(" "))
+        }
+    }
+}
+
+/// pull the return type out of a `(Effect (-> (arg-types...) ret-type))`
+/// annotation
+fn ret_type_of_annotation(e: &Expr) -> Option<String> {
+    if let Expr::List(v, _) = e {
+        if let Some(Expr::List(arrow, _)) = v.get(1) {
+            if let Some(Expr::Id(op, _)) = arrow.first() {
+                if op == "->" {
+                    return arrow.get(2).map(format_type);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn effect_of(expr: &Expr) -> Result<Effect, SemErr> {
+    if let Expr::List(v, span) = expr {
+        if let Some(Expr::Id(head, _)) = v.first() {
+            return match head.as_str() {
+                "Pure" => Ok(Effect::Pure),
+                "IO" => Ok(Effect::IO),
+                other => err(format!("unknown effect `{}`", other), span.start),
+            };
+        }
+        return err("malformed effect/type annotation".to_string(), span.start);
+    }
+    err("malformed effect/type annotation".to_string(), expr.pos())
+}
+
+fn ids(exprs: &[Expr], pos: Pos) -> Result<Vec<String>, SemErr> {
+    let mut v = Vec::new();
+    for e in exprs {
+        match e {
+            Expr::Id(s, _) => v.push(s.clone()),
+            _ => return err("expected an identifier".to_string(), pos),
+        }
+    }
+    Ok(v)
+}
+
+/// check a single top level `(export ...)` / `(defun ...)` form and add
+/// it to `ctx`
+fn add_fun(ctx: &mut Context, v: &[Expr], pos: Pos, exported: bool) -> Result<(), SemErr> {
+    // (export|defun name (params...) (Effect (-> (arg-types...) ret-type)) body)
+    let name = match v.get(1) {
+        Some(Expr::Id(s, _)) => s.clone(),
+        _ => return err("expected a function name".to_string(), pos),
+    };
+    let params = match v.get(2) {
+        Some(Expr::List(ps, ppos)) => ids(ps, ppos.start)?,
+        _ => return err("expected a parameter list".to_string(), pos),
+    };
+    let (effect, effect_span, ret_type) = match v.get(3) {
+        Some(e) => (
+            effect_of(e)?,
+            e.span(),
+            ret_type_of_annotation(e).unwrap_or_else(|| "?".to_string()),
+        ),
+        None => return err("expected an effect/type annotation".to_string(), pos),
+    };
+    let body = match v.get(4) {
+        Some(e) => e.clone(),
+        None => return err("expected a function body".to_string(), pos),
+    };
+
+    if ctx.funs.contains_key(&name) {
+        return err(format!("`{}` is already defined", name), pos);
+    }
+
+    let mut calls = Vec::new();
+    collect_calls(&body, &mut calls);
+
+    if effect != Effect::IO {
+        if let Some((extern_name, call_span, _)) =
+            calls.iter().find(|(c, _, _)| ctx.externs.contains_key(c))
+        {
+            let diagnostic = Diagnostic::new(
+                "E0extern-io",
+                format!(
+                    "extern `{}` may only be called from an `IO` function",
+                    extern_name
+                ),
+                *call_span,
+            )
+            .with_label(effect_span, "declared here".to_string())
+            .with_help(format!("mark this function's effect as `IO` to call `{}`", extern_name));
+            return err_diagnostic(diagnostic);
+        }
+    }
+
+    ctx.funs.insert(
+        name,
+        FuncDef {
+            params,
+            body,
+            effect,
+            exported,
+            pos,
+            effect_span,
+            ret_type,
+            calls,
+        },
+    );
+    Ok(())
+}
+
+/// collect every identifier applied as a function anywhere in `e`,
+/// including inside `if`/`match`/`lambda` bodies, together with its call
+/// site span and argument count. Best-effort: it does not track which
+/// names are shadowed by a `lambda` parameter or a `match` binding, so it
+/// may over-collect, but it never misses a real call.
+fn collect_calls(e: &Expr, out: &mut Vec<(String, Span, usize)>) {
+    match e {
+        Expr::Id(_, _) | Expr::Num(_, _) | Expr::Bool(_, _) => {}
+        Expr::Tuple(v, _) => {
+            for sub in v {
+                collect_calls(sub, out);
+            }
+        }
+        Expr::List(v, _) => {
+            if v.is_empty() {
+                return;
+            }
+            if let Expr::Id(head, head_span) = &v[0] {
+                if head == "quote" {
+                    return;
+                }
+                if !matches!(head.as_str(), "if" | "match" | "lambda") {
+                    out.push((head.clone(), *head_span, v.len() - 1));
+                }
+            } else {
+                collect_calls(&v[0], out);
+            }
+            for arg in &v[1..] {
+                collect_calls(arg, out);
+            }
+        }
+    }
+}
+
+/// check every call collected while adding a `defun`/`export` against the
+/// now-complete set of functions in `ctx`, catching a mismatched argument
+/// count with a two-span [`Diagnostic`] pointing at both the call site and
+/// the callee's declaration. A call to a name that turns out to be
+/// unknown, a builtin, or an extern is left for `runtime` to resolve (or
+/// reject) at call time — this only checks calls between `defun`/`export`s,
+/// since those are the only ones whose arity `ctx` already knows.
+fn check_arities(ctx: &Context) -> Result<(), SemErr> {
+    for def in ctx.funs.values() {
+        for (callee_name, call_span, argc) in &def.calls {
+            let callee = match ctx.funs.get(callee_name) {
+                Some(c) => c,
+                None => continue,
+            };
+            if callee.params.len() != *argc {
+                let decl_span = Span {
+                    start: callee.pos,
+                    end: callee.pos,
+                };
+                let diagnostic = Diagnostic::new(
+                    "E0arity-mismatch",
+                    format!(
+                        "`{}` expects {} argument(s), found {}",
+                        callee_name,
+                        callee.params.len(),
+                        argc
+                    ),
+                    *call_span,
+                )
+                .with_label(
+                    decl_span,
+                    format!(
+                        "`{}` declared with {} parameter(s) here",
+                        callee_name,
+                        callee.params.len()
+                    ),
+                );
+                return err_diagnostic(diagnostic);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the `Int`-typed argument positions a builtin expects, for the builtins
+/// [`static_type_of`] already knows how to classify; `None` for anything
+/// else (including `car`/`cdr`/`map`/`fold`, which operate on `Adt` values
+/// `static_type_of` cannot statically describe)
+fn builtin_arg_types(head: &str) -> Option<&'static [&'static str]> {
+    match head {
+        "+" | "-" | "*" | "band" | "bor" | "bxor" | "pow" | "<=" => Some(&["Int", "Int"]),
+        "sqrt" => Some(&["Int"]),
+        _ => None,
+    }
+}
+
+/// check every call to a builtin inside `e` against the `Int`-only
+/// argument types [`builtin_arg_types`] knows, catching a mismatch like
+/// `(+ 1 true)` with a two-span [`Diagnostic`] pointing at the offending
+/// argument and the call that expects it. Best-effort, like
+/// [`static_type_of`]: an argument it cannot classify (a bare parameter
+/// reference, an `if`/`match`/`lambda`, a call through a value) is left
+/// for `runtime` to reject at evaluation time instead.
+fn check_types_expr(e: &Expr, ctx: &Context) -> Result<(), SemErr> {
+    if let Expr::List(v, _) = e {
+        if let Some(Expr::Id(head, head_span)) = v.first() {
+            if head == "quote" {
+                return Ok(());
+            }
+            if let Some(expected) = builtin_arg_types(head) {
+                for (arg, expected_ty) in v[1..].iter().zip(expected) {
+                    if let Some(found_ty) = static_type_of(arg, ctx) {
+                        if found_ty != *expected_ty {
+                            let diagnostic = Diagnostic::new(
+                                "E0type-mismatch",
+                                format!("expected type {} here, but found type {}", expected_ty, found_ty),
+                                arg.span(),
+                            )
+                            .with_label(*head_span, format!("because `{}` expects {} here", head, expected_ty));
+                            return err_diagnostic(diagnostic);
+                        }
+                    }
+                }
+            }
+        }
+        for sub in v {
+            check_types_expr(sub, ctx)?;
+        }
+    } else if let Expr::Tuple(v, _) = e {
+        for sub in v {
+            check_types_expr(sub, ctx)?;
+        }
+    }
+    Ok(())
+}
+
+/// check every `defun`/`export` body in `ctx` for a builtin call whose
+/// argument's statically known type disagrees with what the builtin
+/// expects; see [`check_types_expr`]
+fn check_types(ctx: &Context) -> Result<(), SemErr> {
+    for def in ctx.funs.values() {
+        check_types_expr(&def.body, ctx)?;
+    }
+    Ok(())
+}
+
+/// check a single top level form and add it to `ctx`; shared by
+/// [`exprs2context`], which builds a fresh `Context` from a whole program,
+/// and [`Context::define`], which merges a fragment into an existing one
+fn add_top_level(ctx: &mut Context, e: &Expr) -> Result<(), SemErr> {
+    let (v, pos) = match e {
+        Expr::List(v, span) => (v, span.start),
+        _ => return err("expected a top level form".to_string(), e.pos()),
+    };
+
+    match v.first() {
+        Some(Expr::Id(head, _)) if head == "export" => add_fun(ctx, v, pos, true),
+        Some(Expr::Id(head, _)) if head == "defun" => add_fun(ctx, v, pos, false),
+        Some(Expr::Id(head, _)) if head == "data" => {
+            // ADT declarations only introduce constructor tags, which
+            // `runtime` resolves structurally; nothing to check yet.
+            Ok(())
+        }
+        _ => err("expected `export`, `defun` or `data`".to_string(), pos),
+    }
+}
+
+/// build a [`Context`] from the top level expressions of a program
+pub fn exprs2context(exprs: &LinkedList<Expr>) -> Result<Context, SemErr> {
+    let mut ctx = Context::new();
+
+    for e in exprs {
+        add_top_level(&mut ctx, e)?;
+    }
+    check_arities(&ctx)?;
+    check_types(&ctx)?;
+
+    Ok(ctx)
+}
+
+/// a compiled program: one [`Chunk`] per `defun`/`export`, indexed by the
+/// position of its name in `names` so a [`Chunk`] can reference another by
+/// a plain `usize` instead of a name lookup
+#[cfg(feature = "bytecode-vm")]
+pub struct Program {
+    pub chunks: Vec<Chunk>,
+    pub names: BTreeMap<String, usize>,
+    pub entries: Vec<String>,
+}
+
+#[cfg(feature = "bytecode-vm")]
+impl Context {
+    /// lower every checked `defun`/`export` into bytecode.
+    ///
+    /// This mirrors the tree-walking evaluator in [`crate::runtime`] but
+    /// resolves every local to a stack slot ahead of time, so the VM never
+    /// performs a name lookup at run time, and emits `TailCall` for calls
+    /// in tail position so self-recursive functions run in constant frame
+    /// space.
+    pub fn compile(&self) -> Program {
+        let names: BTreeMap<String, usize> = self
+            .funs
+            .keys()
+            .cloned()
+            .enumerate()
+            .map(|(i, n)| (n, i))
+            .collect();
+        let entries = self
+            .funs
+            .iter()
+            .filter(|(_, d)| d.exported)
+            .map(|(n, _)| n.clone())
+            .collect();
+        let chunks = self
+            .funs
+            .values()
+            .map(|def| crate::runtime::compiler::compile_fun(def, &names))
+            .collect();
+        Program { chunks, names, entries }
+    }
+}